@@ -0,0 +1,112 @@
+//! # Threads bench.
+//!
+//! Compare the threads count and resident memory of periodic sources running on a thread each,
+//! as beamium does by default, and on the scheduler pool used with `parameters.worker-threads`.
+extern crate time;
+#[macro_use(slog_log, slog_crit)]
+extern crate slog;
+#[macro_use]
+extern crate slog_scope;
+#[macro_use]
+extern crate lazy_static;
+
+use std::thread;
+use std::cmp;
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::fs::File;
+use std::io::prelude::*;
+
+#[path = "../src/scheduler.rs"]
+#[allow(dead_code)]
+mod scheduler;
+
+/// Number of simulated sources.
+const SOURCES: usize = 500;
+/// Sources period, in milliseconds.
+const PERIOD: u64 = 100;
+/// Scheduler pool size.
+const WORKERS: usize = 8;
+/// Measure duration, in milliseconds.
+const DURATION: u64 = 2000;
+
+/// Simulated scrape, format a few metrics lines.
+fn scrape(runs: &AtomicUsize) {
+    let mut body = String::new();
+    for i in 0..100 {
+        body.push_str(&format!("{}// bench.metric{{id={}}} {}\n", time::precise_time_ns(), i, i));
+    }
+    // Depend on the body so its formatting is not optimized out
+    runs.fetch_add(cmp::min(body.len(), 1), Ordering::Relaxed);
+}
+
+/// Read a /proc/self/status field.
+fn status(field: &str) -> String {
+    let mut content = String::new();
+    File::open("/proc/self/status")
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .expect("fail to read /proc/self/status");
+    content.lines()
+        .find(|line| line.starts_with(field))
+        .and_then(|line| line.splitn(2, ':').nth(1))
+        .map(|value| String::from(value.trim()))
+        .unwrap_or_default()
+}
+
+/// Print the process threads count and resident memory.
+fn report(mode: &str, runs: &AtomicUsize) {
+    println!("{:<10} threads: {:>4}  rss: {:>10}  scrapes: {}",
+             mode,
+             status("Threads"),
+             status("VmRSS"),
+             runs.load(Ordering::Relaxed));
+}
+
+fn main() {
+    println!("{} sources, {}ms period", SOURCES, PERIOD);
+
+    // A thread per source
+    let sigint = Arc::new(AtomicBool::new(false));
+    let runs = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<thread::JoinHandle<()>> = (0..SOURCES)
+        .map(|_| {
+            let (sigint, runs) = (sigint.clone(), runs.clone());
+            thread::spawn(move || while !sigint.load(Ordering::Relaxed) {
+                scrape(&runs);
+                thread::sleep(Duration::from_millis(PERIOD));
+            })
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(DURATION));
+    report("threads", &runs);
+    sigint.store(true, Ordering::Relaxed);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Scheduler pool
+    let sigint = Arc::new(AtomicBool::new(false));
+    let runs = Arc::new(AtomicUsize::new(0));
+    let workers: Vec<thread::JoinHandle<()>> = (0..WORKERS)
+        .map(|_| {
+            let sigint = sigint.clone();
+            thread::spawn(move || scheduler::worker(sigint))
+        })
+        .collect();
+    for _ in 0..SOURCES {
+        let runs = runs.clone();
+        scheduler::schedule(0,
+                            sigint.clone(),
+                            Box::new(move || {
+                                scrape(&runs);
+                                PERIOD
+                            }));
+    }
+    thread::sleep(Duration::from_millis(DURATION));
+    report("scheduler", &runs);
+    sigint.store(true, Ordering::Relaxed);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
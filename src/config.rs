@@ -2,19 +2,34 @@
 //!
 //! The Config module provides the beamium configuration.
 //! It set defaults and then load config from '/etc', local dir and provided path.
+use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::io;
 use std::fmt;
 use std::string::String;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::error;
 use std::error::Error;
-use yaml_rust::{YamlLoader, ScanError};
+use std::net::SocketAddr;
+use yaml_rust::{Yaml, YamlLoader, ScanError};
+use yaml_rust::yaml::Hash;
 use cast;
 use std::collections::HashMap;
 use regex;
 use slog;
+use hyper;
+use toml;
+use std::ffi::OsStr;
+use std::env;
+use std::mem;
+
+/// Labels values reference to the name of the source which collected the metric.
+pub const SOURCE_NAME: &'static str = "${source.name}";
+
+/// Modules accepted in parameters.log-levels.
+const LOG_MODULES: [&'static str; 8] = ["config", "source", "router", "sink", "stats",
+                                         "health", "scheduler", "log"];
 
 #[derive(Debug)]
 #[derive(Clone)]
@@ -35,41 +50,423 @@ pub struct Source {
     pub period: u64,
     pub format: SourceFormat,
     pub metrics: Option<regex::RegexSet>,
+    pub token: Option<String>,
+    pub token_header: Option<String>,
+    pub token_file: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_ca: Option<String>,
+    pub insecure: bool,
+    pub timeout: Option<u64>,
+    pub retry: u64,
+    pub retry_delay: u64,
+    pub relabel: Vec<Relabel>,
+    pub metrics_allow: Option<regex::RegexSet>,
+    pub metrics_deny: Option<regex::RegexSet>,
+    pub keep_comments: bool,
+    pub headers: HashMap<String, String>,
+    pub basic_auth: Option<BasicAuth>,
+    pub user_agent: Option<String>,
+    pub scrape_protocol: ScrapeProtocol,
+    pub keep_exemplars: bool,
+    pub dir: Option<String>,
+    pub method: Method,
+    pub body: Option<String>,
+    pub content_type: String,
+    pub proxy: Option<String>,
+    pub max_response_size: u64,
+    pub paths: Vec<String>,
+    pub max_metrics_per_scrape: Option<u64>,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Source scrape HTTP method.
+pub enum Method {
+    Get,
+    Post,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Source scrape protocol, negotiated through the Accept header.
+pub enum ScrapeProtocol {
+    Prometheus,
+    OpenMetrics,
 }
 
 #[derive(Debug)]
 #[derive(Clone)]
+#[derive(PartialEq)]
 /// Source format.
 pub enum SourceFormat {
     Prometheus,
     Sensision,
 }
 
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Source basic authentication.
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+}
+
+impl fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "BasicAuth {{ username: {:?}, password: <redacted>, password_file: {:?} }}",
+               self.username,
+               self.password_file)
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+/// Source relabel rule.
+/// `__name__` as source label refers to the metric class.
+pub struct Relabel {
+    pub source_label: String,
+    pub regex: regex::Regex,
+    pub target_label: Option<String>,
+    pub replacement: String,
+    pub action: RelabelAction,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+/// Sink label selector, only metrics whose label value match the regex are kept.
+pub struct LabelSelector {
+    pub label: String,
+    pub regex: regex::Regex,
+}
+
+impl PartialEq for LabelSelector {
+    fn eq(&self, other: &LabelSelector) -> bool {
+        self.label == other.label && self.regex.as_str() == other.regex.as_str()
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Relabel rule action.
+/// Replace set target label, Rename move source label to target label, Drop remove source label.
+pub enum RelabelAction {
+    Replace,
+    Rename,
+    Drop,
+}
+
+impl PartialEq for Relabel {
+    fn eq(&self, other: &Relabel) -> bool {
+        self.source_label == other.source_label &&
+        self.regex.as_str() == other.regex.as_str() &&
+        self.target_label == other.target_label &&
+        self.replacement == other.replacement &&
+        self.action == other.action
+    }
+}
+
 #[derive(Debug)]
 #[derive(Clone)]
 /// Sink config.
 pub struct Sink {
     pub name: String,
-    pub url: String,
-    pub token: String,
+    pub urls: Vec<String>,
+    pub token: Option<String>,
+    pub token_file: Option<String>,
     pub token_header: String,
     pub selector: Option<regex::Regex>,
+    pub selector_mode: SelectorMode,
+    pub labels: HashMap<String, String>,
     pub ttl: u64,
     pub size: u64,
+    pub retry: u64,
+    pub retry_delay: u64,
+    pub max_push_size: Option<u64>,
+    pub keep_alive: bool,
+    pub parallelism: u64,
+    pub format: SinkFormat,
+    pub meta_urls: Vec<String>,
+    pub attributes: HashMap<String, String>,
+    pub proxy: Option<String>,
+    pub headers: HashMap<String, String>,
+    pub user_agent: Option<String>,
+    pub request_compression: Compression,
+    pub max_rate: Option<u64>,
+    pub connect_timeout: u64,
+    pub request_timeout: Option<u64>,
+    pub http_version: HttpVersion,
+    pub label_selector: Option<LabelSelector>,
+    pub preserve_order: bool,
+    pub coalesce: bool,
+    pub coalesce_max_bytes: Option<u64>,
+    pub class_prefix: Option<String>,
+    pub validate: bool,
+    pub sink_type: SinkType,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Sink type.
+pub enum SinkType {
+    Http,
+    Null,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Sink output format.
+pub enum SinkFormat {
+    Warp10,
+    Influx,
+    Graphite,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Sink selector mode.
+/// Drop skips metrics whose class match the selector, Match only keeps them.
+pub enum SelectorMode {
+    Match,
+    Drop,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Metric class mapper.
+pub enum MetricMapper {
+    None,
+    Dots,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Sink HTTP version.
+pub enum HttpVersion {
+    Http1,
+    /// Falls back to HTTP/1.1, the HTTP client does not speak HTTP/2.
+    Http2,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Sink files compression.
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Console log format.
+pub enum LogFormat {
+    Term,
+    Json,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Router sink files durability.
+pub enum Fsync {
+    None,
+    Data,
+    Full,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Router metric lines validation.
+/// Lenient drops malformed lines, Strict leaves files with a malformed line in place.
+pub enum Validation {
+    Off,
+    Lenient,
+    Strict,
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+/// Timestamp unit expected by Warp10.
+pub enum TimestampUnit {
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl TimestampUnit {
+    /// Convert a timestamp in milliseconds.
+    pub fn from_millis(&self, value: i64) -> i64 {
+        match *self {
+            TimestampUnit::Millis => value,
+            TimestampUnit::Micros => value * 1000,
+            TimestampUnit::Nanos => value * 1000 * 1000,
+        }
+    }
+
+    /// Convert a timestamp to nanoseconds.
+    pub fn to_nanos(&self, value: i64) -> i64 {
+        match *self {
+            TimestampUnit::Millis => value * 1000 * 1000,
+            TimestampUnit::Micros => value * 1000,
+            TimestampUnit::Nanos => value,
+        }
+    }
+
+    /// Convert a timestamp in microseconds.
+    pub fn from_micros(&self, value: i64) -> i64 {
+        match *self {
+            TimestampUnit::Millis => value / 1000,
+            TimestampUnit::Micros => value,
+            TimestampUnit::Nanos => value * 1000,
+        }
+    }
 }
 
 #[derive(Debug)]
 #[derive(Clone)]
+#[derive(PartialEq)]
 /// Parameters config.
 pub struct Parameters {
     pub scan_period: u64,
     pub sink_dir: String,
     pub source_dir: String,
+    pub source_extension: String,
     pub batch_size: u64,
     pub batch_count: u64,
+    pub batch_lines: Option<u64>,
     pub log_file: String,
     pub log_level: slog::Level,
     pub timeout: u64,
+    pub shutdown_timeout: u64,
+    pub sink_dir_max_size: Option<u64>,
+    pub compression: Compression,
+    pub self_metrics: bool,
+    pub listen: Option<String>,
+    pub metric_mapper: MetricMapper,
+    pub drop_nan: bool,
+    pub dead_letter_dir: Option<String>,
+    pub dead_letter_status: Vec<u16>,
+    pub scrape_jitter: u64,
+    pub jitter_seed: Option<u64>,
+    pub log_format: LogFormat,
+    pub log_file_max_size: Option<u64>,
+    pub log_file_keep: u64,
+    pub sanitize_le: bool,
+    pub timestamp_unit: TimestampUnit,
+    pub dedup: bool,
+    pub router_min_rest: u64,
+    pub router_rest_time: u64,
+    pub router_read_parallelism: u64,
+    pub user_agent: String,
+    pub fsync: Fsync,
+    pub scrape_concurrency: Option<u64>,
+    pub worker_threads: Option<u64>,
+    pub encode_labels: bool,
+    pub corrupt_dir: Option<String>,
+    pub validation: Validation,
+    pub tenant_label: Option<String>,
+    pub tenants: HashMap<String, String>,
+    pub tenant_default: Option<String>,
+    pub dns_cache_ttl: Option<u64>,
+    pub cleanup_orphans: bool,
+    pub sort_labels: bool,
+    pub restart_on_panic: bool,
+    pub log_levels: HashMap<String, slog::Level>,
+    pub labels_file: Option<String>,
+    pub labels_file_period: u64,
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+}
+
+impl PartialEq for Source {
+    fn eq(&self, other: &Source) -> bool {
+        // RegexSet does not implement PartialEq, compare its patterns through Debug instead
+        self.name == other.name &&
+        self.url == other.url &&
+        self.period == other.period &&
+        self.format == other.format &&
+        format!("{:?}", self.metrics) == format!("{:?}", other.metrics) &&
+        self.token == other.token &&
+        self.token_header == other.token_header &&
+        self.token_file == other.token_file &&
+        self.tls_cert == other.tls_cert &&
+        self.tls_key == other.tls_key &&
+        self.tls_ca == other.tls_ca &&
+        self.insecure == other.insecure &&
+        self.timeout == other.timeout &&
+        self.retry == other.retry &&
+        self.retry_delay == other.retry_delay &&
+        self.relabel == other.relabel &&
+        format!("{:?}", self.metrics_allow) == format!("{:?}", other.metrics_allow) &&
+        format!("{:?}", self.metrics_deny) == format!("{:?}", other.metrics_deny) &&
+        self.keep_comments == other.keep_comments &&
+        self.headers == other.headers &&
+        self.basic_auth == other.basic_auth &&
+        self.user_agent == other.user_agent &&
+        self.scrape_protocol == other.scrape_protocol &&
+        self.keep_exemplars == other.keep_exemplars &&
+        self.dir == other.dir &&
+        self.method == other.method &&
+        self.body == other.body &&
+        self.content_type == other.content_type &&
+        self.proxy == other.proxy &&
+        self.max_response_size == other.max_response_size &&
+        self.paths == other.paths &&
+        self.max_metrics_per_scrape == other.max_metrics_per_scrape
+    }
+}
+
+impl PartialEq for Sink {
+    fn eq(&self, other: &Sink) -> bool {
+        self.name == other.name &&
+        self.urls == other.urls &&
+        self.token == other.token &&
+        self.token_file == other.token_file &&
+        self.token_header == other.token_header &&
+        self.selector.as_ref().map(|s| s.as_str()) ==
+        other.selector.as_ref().map(|s| s.as_str()) &&
+        self.selector_mode == other.selector_mode &&
+        self.labels == other.labels &&
+        self.ttl == other.ttl &&
+        self.size == other.size &&
+        self.retry == other.retry &&
+        self.retry_delay == other.retry_delay &&
+        self.max_push_size == other.max_push_size &&
+        self.keep_alive == other.keep_alive &&
+        self.parallelism == other.parallelism &&
+        self.format == other.format &&
+        self.meta_urls == other.meta_urls &&
+        self.attributes == other.attributes &&
+        self.proxy == other.proxy &&
+        self.headers == other.headers &&
+        self.user_agent == other.user_agent &&
+        self.request_compression == other.request_compression &&
+        self.max_rate == other.max_rate &&
+        self.connect_timeout == other.connect_timeout &&
+        self.request_timeout == other.request_timeout &&
+        self.http_version == other.http_version &&
+        self.label_selector == other.label_selector &&
+        self.preserve_order == other.preserve_order &&
+        self.coalesce == other.coalesce &&
+        self.coalesce_max_bytes == other.coalesce_max_bytes &&
+        self.class_prefix == other.class_prefix &&
+        self.validate == other.validate &&
+        self.sink_type == other.sink_type
+    }
 }
 
 #[derive(Debug)]
@@ -77,6 +474,7 @@ pub struct Parameters {
 pub enum ConfigError {
     Io(io::Error),
     Yaml(ScanError),
+    Toml(toml::de::Error),
     Regex(regex::Error),
     Format(Box<Error>),
 }
@@ -91,6 +489,11 @@ impl From<ScanError> for ConfigError {
         ConfigError::Yaml(err)
     }
 }
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> ConfigError {
+        ConfigError::Toml(err)
+    }
+}
 impl From<regex::Error> for ConfigError {
     fn from(err: regex::Error) -> ConfigError {
         ConfigError::Regex(err)
@@ -117,6 +520,7 @@ impl fmt::Display for ConfigError {
         match *self {
             ConfigError::Io(ref err) => err.fmt(f),
             ConfigError::Yaml(ref err) => err.fmt(f),
+            ConfigError::Toml(ref err) => err.fmt(f),
             ConfigError::Regex(ref err) => err.fmt(f),
             ConfigError::Format(ref err) => err.fmt(f),
         }
@@ -128,6 +532,7 @@ impl error::Error for ConfigError {
         match *self {
             ConfigError::Io(ref err) => err.description(),
             ConfigError::Yaml(ref err) => err.description(),
+            ConfigError::Toml(ref err) => err.description(),
             ConfigError::Regex(ref err) => err.description(),
             ConfigError::Format(ref err) => err.description(),
         }
@@ -137,6 +542,7 @@ impl error::Error for ConfigError {
         match *self {
             ConfigError::Io(ref err) => Some(err),
             ConfigError::Yaml(ref err) => Some(err),
+            ConfigError::Toml(ref err) => Some(err),
             ConfigError::Regex(ref err) => Some(err),
             ConfigError::Format(ref err) => Some(err.as_ref()),
         }
@@ -157,38 +563,226 @@ pub fn load_config(config_path: &str) -> Result<Config, ConfigError> {
             scan_period: 1000,
             sink_dir: String::from("sinks"),
             source_dir: String::from("sources"),
+            source_extension: String::from("metrics"),
             batch_size: 200000,
             batch_count: 250,
+            batch_lines: None,
             log_file: String::from(env!("CARGO_PKG_NAME")) + ".log",
             log_level: slog::Level::Info,
             timeout: 300,
+            shutdown_timeout: 10000,
+            sink_dir_max_size: None,
+            compression: Compression::None,
+            self_metrics: false,
+            listen: None,
+            metric_mapper: MetricMapper::None,
+            drop_nan: false,
+            dead_letter_dir: None,
+            dead_letter_status: vec![400],
+            scrape_jitter: 0,
+            jitter_seed: None,
+            log_format: LogFormat::Term,
+            log_file_max_size: None,
+            log_file_keep: 5,
+            sanitize_le: false,
+            timestamp_unit: TimestampUnit::Micros,
+            dedup: false,
+            router_min_rest: 10,
+            router_rest_time: 10,
+            router_read_parallelism: 1,
+            user_agent: format!("{}/{} ({})",
+                                env!("CARGO_PKG_NAME"),
+                                env!("CARGO_PKG_VERSION"),
+                                ::COMMIT),
+            fsync: Fsync::None,
+            scrape_concurrency: None,
+            worker_threads: None,
+            encode_labels: false,
+            corrupt_dir: None,
+            validation: Validation::Off,
+            tenant_label: None,
+            tenants: HashMap::new(),
+            tenant_default: None,
+            dns_cache_ttl: None,
+            cleanup_orphans: false,
+            sort_labels: false,
+            restart_on_panic: true,
+            log_levels: HashMap::new(),
+            labels_file: None,
+            labels_file_period: 10000,
+            file_mode: None,
+            dir_mode: None,
         },
     };
 
     // Load from etc
     if Path::new("/etc/beamium/config.yaml").exists() {
         try!(load_path("/etc/beamium/config.yaml", &mut config));
+    } else if Path::new("/etc/beamium/config.toml").exists() {
+        try!(load_path("/etc/beamium/config.toml", &mut config));
     }
 
     // Load local
     if Path::new("config.yaml").exists() {
         try!(load_path("config.yaml", &mut config));
+    } else if Path::new("config.toml").exists() {
+        try!(load_path("config.toml", &mut config));
     }
 
     // Load from provided path
     if !config_path.is_empty() {
-        try!(load_path(config_path, &mut config));
+        if Path::new(config_path).is_dir() {
+            try!(load_dir(config_path, &mut config));
+        } else {
+            try!(load_path(config_path, &mut config));
+        }
     }
 
     Ok(config)
 }
 
+/// Extend config from every config file of a directory, in lexicographic order.
+/// A source or sink defined in two files of the directory is an error.
+fn load_dir(dir: &str, config: &mut Config) -> Result<(), ConfigError> {
+    let mut paths = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        let config_file = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") | Some("toml") => true,
+            _ => false,
+        };
+        if config_file {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    // Each fragment is loaded on its own to know which one defined a name
+    let mut sources: HashMap<String, PathBuf> = HashMap::new();
+    let mut sinks: HashMap<String, PathBuf> = HashMap::new();
+    for path in paths {
+        let mut fragment = config.clone();
+        fragment.sources = Vec::new();
+        fragment.sinks = Vec::new();
+        try!(load_path(&path, &mut fragment));
+
+        // Fragments still override the /etc and local definitions
+        let defined = mem::replace(&mut fragment.sources, config.sources.clone());
+        for source in defined {
+            if let Some(first) = sources.get(&source.name) {
+                return Err(format!("sources.{} is defined in both {} and {}",
+                                   source.name,
+                                   first.display(),
+                                   path.display())
+                    .into());
+            }
+            sources.insert(source.name.clone(), path.clone());
+            let name = source.name.clone();
+            fragment.sources.retain(|source| source.name != name);
+            fragment.sources.push(source);
+        }
+
+        let defined = mem::replace(&mut fragment.sinks, config.sinks.clone());
+        for sink in defined {
+            if let Some(first) = sinks.get(&sink.name) {
+                return Err(format!("sinks.{} is defined in both {} and {}",
+                                   sink.name,
+                                   first.display(),
+                                   path.display())
+                    .into());
+            }
+            sinks.insert(sink.name.clone(), path.clone());
+            let name = sink.name.clone();
+            fragment.sinks.retain(|sink| sink.name != name);
+            fragment.sinks.push(sink);
+        }
+
+        *config = fragment;
+    }
+
+    Ok(())
+}
+
+/// Directories the router reads source files from, the default one first.
+pub fn source_dirs(config: &Config) -> Vec<String> {
+    let mut dirs = vec![config.parameters.source_dir.clone()];
+    for source in &config.sources {
+        if let Some(ref dir) = source.dir {
+            if !dirs.contains(dir) {
+                dirs.push(dir.clone());
+            }
+        }
+    }
+
+    dirs
+}
+
+/// Check config.
+///
+/// Ensure directories can be created and URLs are valid.
+pub fn check(config: &Config) -> Result<(), ConfigError> {
+    for dir in source_dirs(config) {
+        try!(check_dir(&dir));
+    }
+    try!(check_dir(&config.parameters.sink_dir));
+
+    for source in &config.sources {
+        try!(hyper::Url::parse(&source.url)
+            .map_err(|err| format!("sources.{}.url is invalid: {}", source.name, err)));
+    }
+    for sink in &config.sinks {
+        for url in &sink.urls {
+            try!(hyper::Url::parse(url)
+                .map_err(|err| format!("sinks.{}.url is invalid: {}", sink.name, err)));
+        }
+    }
+
+    // Tenants are routed by sink name
+    let targets = config.parameters.tenants.values().chain(config.parameters.tenant_default.iter());
+    for target in targets {
+        if !config.sinks.iter().any(|sink| sink.name == *target) {
+            return Err(format!("parameters.tenants sink {} does not exist", target).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Check a directory exists or can be created.
+fn check_dir(dir: &str) -> Result<(), ConfigError> {
+    // Look for the first existing ancestor
+    let mut path = Path::new(dir);
+    while !path.exists() {
+        path = match path.parent() {
+            None => return Ok(()),
+            Some(p) if p.as_os_str().is_empty() => Path::new("."),
+            Some(p) => p,
+        };
+    }
+
+    let meta = try!(path.metadata());
+    if !meta.is_dir() {
+        return Err(format!("{} is not a directory", path.display()).into());
+    }
+    if meta.permissions().readonly() {
+        return Err(format!("{} is read only", path.display()).into());
+    }
+
+    Ok(())
+}
+
 /// Extend confif from file.
+/// The parser is selected by file extension, TOML files are converted to YAML documents.
 fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), ConfigError> {
-    let mut file = try!(File::open(file_path));
+    let mut file = try!(File::open(&file_path));
     let mut contents = String::new();
     try!(file.read_to_string(&mut contents));
-    let docs = try!(YamlLoader::load_from_str(&contents));
+    let docs = if file_path.as_ref().extension() == Some(OsStr::new("toml")) {
+        vec![toml_to_yaml(try!(contents.parse::<toml::Value>()))]
+    } else {
+        try!(YamlLoader::load_from_str(&contents))
+    };
+    let docs: Vec<Yaml> = try!(docs.into_iter().map(expand_env).collect());
 
     for doc in &docs {
         if !doc["sources"].is_badvalue() {
@@ -236,119 +830,1007 @@ fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), Co
                     Some(try!(regex::RegexSet::new(&metrics)))
                 };
 
-                config.sources.push(Source {
-                    name: String::from(name),
-                    url: String::from(url),
-                    period: period,
-                    format: format,
-                    metrics: metrics,
-                })
-            }
-        }
-
-        if !doc["sinks"].is_badvalue() {
-            let sinks = try!(doc["sinks"].as_hash().ok_or("sinks should be a map"));
-            for (k, v) in sinks {
-                let name = try!(k.as_str().ok_or("sinks keys should be a string"));
-                let url = try!(v["url"]
-                    .as_str()
-                    .ok_or(format!("sinks.{}.url is required and should be a string", name)));
-                let token = try!(v["token"]
-                    .as_str()
-                    .ok_or(format!("sinks.{}.token is required and should be a string", name)));
+                let token = if v["token"].is_badvalue() {
+                    None
+                } else {
+                    Some(String::from(try!(v["token"]
+                        .as_str()
+                        .ok_or(format!("sources.{}.token should be a string", name)))))
+                };
                 let token_header = if v["token-header"].is_badvalue() {
-                    "X-Warp10-Token"
+                    None
                 } else {
-                    try!(v["token-header"]
+                    Some(String::from(try!(v["token-header"]
                         .as_str()
-                        .ok_or(format!("sinks.{}.token-header should be a string", name)))
+                        .ok_or(format!("sources.{}.token-header should be a string", name)))))
                 };
-
-                let selector = if v["selector"].is_badvalue() {
+                if token_header.as_ref().map_or(false, |header| !is_header_name(header)) {
+                    return Err(format!("sources.{}.token-header is not a valid header name", name)
+                        .into());
+                }
+                let token_file = if v["token-file"].is_badvalue() {
                     None
                 } else {
-                    Some(try!(regex::Regex::new(try!(v["selector"]
+                    Some(String::from(try!(v["token-file"]
                         .as_str()
-                        .ok_or(format!("sinks.{}.selector is invalid", name))))))
+                        .ok_or(format!("sources.{}.token-file should be a string", name)))))
                 };
+                if token.is_some() && token_file.is_some() {
+                    return Err(format!("sources.{} should not have both token and token-file",
+                                       name)
+                        .into());
+                }
 
-                let ttl = if v["ttl"].is_badvalue() {
-                    3600
+                let tls_cert = try!(tls_file(v, name, "tls-cert"));
+                let tls_key = try!(tls_file(v, name, "tls-key"));
+                let tls_ca = try!(tls_file(v, name, "tls-ca"));
+                if tls_cert.is_some() != tls_key.is_some() {
+                    return Err(format!("sources.{} should have both tls-cert and tls-key", name)
+                        .into());
+                }
+
+                let insecure = if v["insecure"].is_badvalue() {
+                    false
                 } else {
-                    let ttl = try!(v["ttl"]
-                        .as_i64()
-                        .ok_or(format!("sinks.{}.ttl should be a number", name)));
-                    try!(cast::u64(ttl)
-                        .map_err(|_| format!("sinks.{}.ttl should be a positive number", name)))
+                    try!(v["insecure"]
+                        .as_bool()
+                        .ok_or(format!("sources.{}.insecure should be a boolean", name)))
                 };
 
-                let size = if v["size"].is_badvalue() {
-                    1073741824
+                let timeout = if v["timeout"].is_badvalue() {
+                    None
                 } else {
-                    let size = try!(v["size"]
+                    let timeout = try!(v["timeout"]
                         .as_i64()
-                        .ok_or(format!("sinks.{}.size should be a number", name)));
-                    try!(cast::u64(size)
-                        .map_err(|_| format!("sinks.{}.size should be a positive number", name)))
+                        .ok_or(format!("sources.{}.timeout should be a number", name)));
+                    Some(try!(cast::u64(timeout)
+                        .map_err(|_| format!("sources.{}.timeout should be a positive number",
+                                             name))))
                 };
 
-                config.sinks.push(Sink {
-                    name: String::from(name),
-                    url: String::from(url),
-                    token: String::from(token),
-                    token_header: String::from(token_header),
-                    selector: selector,
-                    ttl: ttl,
-                    size: size,
-                })
-            }
-        }
+                let retry = if v["retry"].is_badvalue() {
+                    0
+                } else {
+                    let retry = try!(v["retry"]
+                        .as_i64()
+                        .ok_or(format!("sources.{}.retry should be a number", name)));
+                    try!(cast::u64(retry)
+                        .map_err(|_| format!("sources.{}.retry should be a positive number", name)))
+                };
+                let retry_delay = if v["retry-delay"].is_badvalue() {
+                    1000
+                } else {
+                    let retry_delay = try!(v["retry-delay"]
+                        .as_i64()
+                        .ok_or(format!("sources.{}.retry-delay should be a number", name)));
+                    try!(cast::u64(retry_delay)
+                        .map_err(|_| format!("sources.{}.retry-delay should be a positive number",
+                                             name)))
+                };
 
-        if !doc["labels"].is_badvalue() {
-            let labels = try!(doc["labels"].as_hash().ok_or("labels should be a map"));
-            for (k, v) in labels {
-                let name = try!(k.as_str().ok_or("labels keys should be a string"));
-                let value = try!(v.as_str()
-                    .ok_or(format!("labels.{} value should be a string", name)));
-                config.labels.insert(String::from(name), String::from(value));
-            }
-        }
+                let mut relabel = Vec::new();
+                if !v["relabel"].is_badvalue() {
+                    let rules = try!(v["relabel"]
+                        .as_vec()
+                        .ok_or(format!("sources.{}.relabel should be an array", name)));
+                    for rule in rules {
+                        let source_label = if rule["source-label"].is_badvalue() {
+                            String::from("__name__")
+                        } else {
+                            String::from(try!(rule["source-label"].as_str().ok_or(format!(
+                                "sources.{}.relabel.source-label should be a string", name))))
+                        };
+                        let regex = if rule["regex"].is_badvalue() {
+                            try!(regex::Regex::new("(.*)"))
+                        } else {
+                            let regex = try!(rule["regex"].as_str().ok_or(format!(
+                                "sources.{}.relabel.regex should be a string", name)));
+                            // Anchor the regex so it match the whole value
+                            try!(regex::Regex::new(&format!("^(?:{})$", regex)))
+                        };
+                        let target_label = if rule["target-label"].is_badvalue() {
+                            None
+                        } else {
+                            Some(String::from(try!(rule["target-label"].as_str().ok_or(format!(
+                                "sources.{}.relabel.target-label should be a string", name)))))
+                        };
+                        let replacement = if rule["replacement"].is_badvalue() {
+                            String::from("$1")
+                        } else {
+                            String::from(try!(rule["replacement"].as_str().ok_or(format!(
+                                "sources.{}.relabel.replacement should be a string", name))))
+                        };
+                        let action = if rule["action"].is_badvalue() {
+                            RelabelAction::Replace
+                        } else {
+                            let action = try!(rule["action"].as_str().ok_or(format!(
+                                "sources.{}.relabel.action should be a string", name)));
+                            if action == "replace" {
+                                RelabelAction::Replace
+                            } else if action == "rename" {
+                                RelabelAction::Rename
+                            } else if action == "drop" {
+                                RelabelAction::Drop
+                            } else {
+                                return Err(format!("sources.{}.relabel.action should be \
+                                                    'replace', 'rename' or 'drop'",
+                                                   name)
+                                    .into());
+                            }
+                        };
+                        if action != RelabelAction::Drop && target_label.is_none() {
+                            return Err(format!("sources.{}.relabel.target-label is required",
+                                               name)
+                                .into());
+                        }
+                        if action != RelabelAction::Replace && source_label == "__name__" {
+                            return Err(format!("sources.{}.relabel.source-label is required",
+                                               name)
+                                .into());
+                        }
 
-        if !doc["parameters"].is_badvalue() {
-            if !doc["parameters"]["source-dir"].is_badvalue() {
-                let source_dir = try!(doc["parameters"]["source-dir"]
-                    .as_str()
-                    .ok_or(format!("parameters.source-dir should be a string")));
-                config.parameters.source_dir = String::from(source_dir);
-            }
+                        relabel.push(Relabel {
+                            source_label: source_label,
+                            regex: regex,
+                            target_label: target_label,
+                            replacement: replacement,
+                            action: action,
+                        });
+                    }
+                }
 
-            if !doc["parameters"]["sink-dir"].is_badvalue() {
-                let sink_dir = try!(doc["parameters"]["sink-dir"]
-                    .as_str()
-                    .ok_or(format!("parameters.sink-dir should be a string")));
-                config.parameters.sink_dir = String::from(sink_dir);
-            }
+                let metrics_allow = try!(class_set(v, name, "metrics-allow"));
+                let metrics_deny = try!(class_set(v, name, "metrics-deny"));
 
-            if !doc["parameters"]["scan-period"].is_badvalue() {
-                let scan_period = try!(doc["parameters"]["scan-period"]
-                    .as_i64()
-                    .ok_or(format!("parameters.scan-period should be a number")));
-                let scan_period = try!(cast::u64(scan_period)
-                    .map_err(|_| format!("parameters.scan-period is invalid")));
-                config.parameters.scan_period = scan_period;
-            }
+                let keep_comments = if v["keep-comments"].is_badvalue() {
+                    false
+                } else {
+                    try!(v["keep-comments"]
+                        .as_bool()
+                        .ok_or(format!("sources.{}.keep-comments should be a boolean", name)))
+                };
 
-            if !doc["parameters"]["batch-size"].is_badvalue() {
-                let batch_size = try!(doc["parameters"]["batch-size"]
-                    .as_i64()
-                    .ok_or(format!("parameters.batch-size should be a number")));
-                let batch_size = try!(cast::u64(batch_size)
-                    .map_err(|_| format!("parameters.batch-size is invalid")));
-                config.parameters.batch_size = batch_size;
-            }
+                let mut headers = HashMap::new();
+                if !v["headers"].is_badvalue() {
+                    let values = try!(v["headers"]
+                        .as_hash()
+                        .ok_or(format!("sources.{}.headers should be a map", name)));
+                    for (k, v) in values {
+                        let header = try!(k.as_str()
+                            .ok_or(format!("sources.{}.headers keys should be a string", name)));
+                        if !is_header_name(header) {
+                            return Err(format!("sources.{}.headers.{} is not a valid header name",
+                                               name,
+                                               header)
+                                .into());
+                        }
+                        let value = try!(v.as_str()
+                            .ok_or(format!("sources.{}.headers.{} value should be a string",
+                                           name,
+                                           header)));
+                        headers.insert(String::from(header), String::from(value));
+                    }
+                }
 
-            if !doc["parameters"]["batch-count"].is_badvalue() {
-                let batch_count = try!(doc["parameters"]["batch-count"]
+                let basic_auth = if v["basic-auth"].is_badvalue() {
+                    None
+                } else {
+                    let auth = &v["basic-auth"];
+                    let username = try!(auth["username"].as_str().ok_or(format!(
+                        "sources.{}.basic-auth.username is required and should be a string",
+                        name)));
+                    let password = if auth["password"].is_badvalue() {
+                        None
+                    } else {
+                        Some(String::from(try!(auth["password"].as_str().ok_or(format!(
+                            "sources.{}.basic-auth.password should be a string", name)))))
+                    };
+                    let password_file = if auth["password-file"].is_badvalue() {
+                        None
+                    } else {
+                        Some(String::from(try!(auth["password-file"].as_str().ok_or(format!(
+                            "sources.{}.basic-auth.password-file should be a string", name)))))
+                    };
+                    if password.is_some() && password_file.is_some() {
+                        return Err(format!("sources.{}.basic-auth should not have both password \
+                                            and password-file",
+                                           name)
+                            .into());
+                    }
+                    if token.is_some() || token_file.is_some() {
+                        return Err(format!("sources.{} should not have both basic-auth and token",
+                                           name)
+                            .into());
+                    }
+
+                    Some(BasicAuth {
+                        username: String::from(username),
+                        password: password,
+                        password_file: password_file,
+                    })
+                };
+
+                let user_agent = if v["user-agent"].is_badvalue() {
+                    None
+                } else {
+                    Some(String::from(try!(v["user-agent"]
+                        .as_str()
+                        .ok_or(format!("sources.{}.user-agent should be a string", name)))))
+                };
+
+                let scrape_protocol = if v["scrape-protocol"].is_badvalue() {
+                    ScrapeProtocol::Prometheus
+                } else {
+                    let p = try!(v["scrape-protocol"]
+                        .as_str()
+                        .ok_or(format!("sources.{}.scrape-protocol should be a string", name)));
+
+                    if p == "prometheus" {
+                        ScrapeProtocol::Prometheus
+                    } else if p == "openmetrics" {
+                        ScrapeProtocol::OpenMetrics
+                    } else {
+                        return Err(format!("sources.{}.scrape-protocol should be 'prometheus' or \
+                                            'openmetrics'",
+                                           name)
+                            .into());
+                    }
+                };
+                let keep_exemplars = if v["keep-exemplars"].is_badvalue() {
+                    false
+                } else {
+                    try!(v["keep-exemplars"]
+                        .as_bool()
+                        .ok_or(format!("sources.{}.keep-exemplars should be a boolean", name)))
+                };
+
+                let dir = if v["dir"].is_badvalue() {
+                    None
+                } else {
+                    Some(String::from(try!(v["dir"]
+                        .as_str()
+                        .ok_or(format!("sources.{}.dir should be a string", name)))))
+                };
+
+                let method = if v["method"].is_badvalue() {
+                    Method::Get
+                } else {
+                    let method = try!(v["method"]
+                        .as_str()
+                        .ok_or(format!("sources.{}.method should be a string", name)));
+                    if method == "GET" {
+                        Method::Get
+                    } else if method == "POST" {
+                        Method::Post
+                    } else {
+                        return Err(format!("sources.{}.method should be 'GET' or 'POST'", name)
+                            .into());
+                    }
+                };
+
+                let body = if v["body"].is_badvalue() {
+                    None
+                } else {
+                    Some(String::from(try!(v["body"]
+                        .as_str()
+                        .ok_or(format!("sources.{}.body should be a string", name)))))
+                };
+                if body.is_some() && method != Method::Post {
+                    return Err(format!("sources.{}.body requires method POST", name).into());
+                }
+
+                let content_type = if v["content-type"].is_badvalue() {
+                    String::from("text/plain")
+                } else {
+                    String::from(try!(v["content-type"]
+                        .as_str()
+                        .ok_or(format!("sources.{}.content-type should be a string", name))))
+                };
+
+                let proxy = if v["proxy"].is_badvalue() {
+                    None
+                } else {
+                    Some(String::from(try!(v["proxy"]
+                        .as_str()
+                        .ok_or(format!("sources.{}.proxy should be a string", name)))))
+                };
+                if let Some(ref proxy) = proxy {
+                    let url = try!(hyper::Url::parse(proxy)
+                        .map_err(|err| format!("sources.{}.proxy is invalid: {}", name, err)));
+                    if url.host_str().is_none() {
+                        return Err(format!("sources.{}.proxy should have a host", name).into());
+                    }
+                }
+
+                let max_response_size = if v["max-response-size"].is_badvalue() {
+                    104857600
+                } else {
+                    let max_response_size = try!(v["max-response-size"]
+                        .as_i64()
+                        .ok_or(format!("sources.{}.max-response-size should be a number", name)));
+                    try!(cast::u64(max_response_size)
+                        .map_err(|_| {
+                            format!("sources.{}.max-response-size should be a positive number",
+                                    name)
+                        }))
+                };
+
+                let mut paths = Vec::new();
+                if !v["paths"].is_badvalue() {
+                    let values = try!(v["paths"]
+                        .as_vec()
+                        .ok_or(format!("sources.{}.paths should be an array", name)));
+                    for v in values {
+                        let path = try!(v.as_str()
+                            .ok_or(format!("sources.{}.paths should contain strings", name)));
+                        if !path.starts_with('/') {
+                            return Err(format!("sources.{}.paths should start with /", name)
+                                .into());
+                        }
+                        paths.push(String::from(path));
+                    }
+                }
+
+                let max_metrics_per_scrape = if v["max-metrics-per-scrape"].is_badvalue() {
+                    None
+                } else {
+                    let max_metrics_per_scrape = try!(v["max-metrics-per-scrape"]
+                        .as_i64()
+                        .ok_or(format!("sources.{}.max-metrics-per-scrape should be a number",
+                                       name)));
+                    if max_metrics_per_scrape <= 0 {
+                        return Err(format!("sources.{}.max-metrics-per-scrape should be a \
+                                            positive number",
+                                           name)
+                            .into());
+                    }
+                    Some(max_metrics_per_scrape as u64)
+                };
+
+                // Names are used for threads and files, a later definition overrides
+                config.sources.retain(|source| source.name != name);
+                config.sources.push(Source {
+                    name: String::from(name),
+                    url: String::from(url),
+                    period: period,
+                    format: format,
+                    metrics: metrics,
+                    token: token,
+                    token_header: token_header,
+                    token_file: token_file,
+                    tls_cert: tls_cert,
+                    tls_key: tls_key,
+                    tls_ca: tls_ca,
+                    insecure: insecure,
+                    timeout: timeout,
+                    retry: retry,
+                    retry_delay: retry_delay,
+                    relabel: relabel,
+                    metrics_allow: metrics_allow,
+                    metrics_deny: metrics_deny,
+                    keep_comments: keep_comments,
+                    headers: headers,
+                    basic_auth: basic_auth,
+                    user_agent: user_agent,
+                    scrape_protocol: scrape_protocol,
+                    keep_exemplars: keep_exemplars,
+                    dir: dir,
+                    method: method,
+                    body: body,
+                    content_type: content_type,
+                    proxy: proxy,
+                    max_response_size: max_response_size,
+                    paths: paths,
+                    max_metrics_per_scrape: max_metrics_per_scrape,
+                })
+            }
+        }
+
+        if !doc["sinks"].is_badvalue() {
+            let sinks = try!(doc["sinks"].as_hash().ok_or("sinks should be a map"));
+            for (k, v) in sinks {
+                let name = try!(k.as_str().ok_or("sinks keys should be a string"));
+                // YAML reads a bare null as a null value, the null sink must be quoted
+                let sink_type = match v["type"] {
+                    Yaml::BadValue => SinkType::Http,
+                    Yaml::Null => {
+                        return Err(format!("sinks.{}.type is null, quote it as \"null\" to \
+                                            discard metrics",
+                                           name)
+                            .into())
+                    }
+                    ref t => {
+                        let t = try!(t.as_str()
+                            .ok_or(format!("sinks.{}.type should be a string", name)));
+                        if t == "http" {
+                            SinkType::Http
+                        } else if t == "null" {
+                            SinkType::Null
+                        } else {
+                            return Err(format!("sinks.{}.type should be 'http' or 'null'", name)
+                                .into());
+                        }
+                    }
+                };
+
+                // Null sinks push nowhere
+                let urls = if sink_type == SinkType::Null && v["url"].is_badvalue() &&
+                              v["urls"].is_badvalue() {
+                    Vec::new()
+                } else if v["urls"].is_badvalue() {
+                    let url = try!(v["url"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.url is required and should be a string", name)));
+                    vec![String::from(url)]
+                } else {
+                    if !v["url"].is_badvalue() {
+                        return Err(format!("sinks.{} should not have both url and urls", name)
+                            .into());
+                    }
+                    let values = try!(v["urls"]
+                        .as_vec()
+                        .ok_or(format!("sinks.{}.urls should be an array", name)));
+                    let mut urls = Vec::new();
+                    for v in values {
+                        let url = try!(v.as_str()
+                            .ok_or(format!("sinks.{}.urls should contain strings", name)));
+                        urls.push(String::from(url));
+                    }
+                    if urls.is_empty() {
+                        return Err(format!("sinks.{}.urls should not be empty", name).into());
+                    }
+                    urls
+                };
+                let format = if v["format"].is_badvalue() {
+                    SinkFormat::Warp10
+                } else {
+                    let f = try!(v["format"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.format should be a string", name)));
+
+                    if f == "warp10" {
+                        SinkFormat::Warp10
+                    } else if f == "influx" {
+                        SinkFormat::Influx
+                    } else if f == "graphite" {
+                        SinkFormat::Graphite
+                    } else {
+                        return Err(format!("sinks.{}.format should be 'warp10', 'influx' or \
+                                            'graphite'",
+                                           name)
+                            .into());
+                    }
+                };
+
+                let token = if v["token"].is_badvalue() {
+                    None
+                } else {
+                    Some(String::from(try!(v["token"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.token should be a string", name)))))
+                };
+                let token_file = if v["token-file"].is_badvalue() {
+                    None
+                } else {
+                    Some(String::from(try!(v["token-file"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.token-file should be a string", name)))))
+                };
+                // Graphite has no authentication
+                if format == SinkFormat::Graphite {
+                    if token.is_some() || token_file.is_some() {
+                        return Err(format!("sinks.{} graphite format does not support tokens",
+                                           name)
+                            .into());
+                    }
+                } else if (token.is_some() && token_file.is_some()) ||
+                          (sink_type == SinkType::Http && token.is_none() && token_file.is_none()) {
+                    return Err(format!("sinks.{} should have either token or token-file", name)
+                        .into());
+                }
+                let token_header = if v["token-header"].is_badvalue() {
+                    "X-Warp10-Token"
+                } else {
+                    try!(v["token-header"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.token-header should be a string", name)))
+                };
+                if !is_header_name(token_header) {
+                    return Err(format!("sinks.{}.token-header is not a valid header name", name)
+                        .into());
+                }
+
+                let selector = if v["selector"].is_badvalue() {
+                    None
+                } else {
+                    Some(try!(regex::Regex::new(try!(v["selector"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.selector is invalid", name))))))
+                };
+
+                let selector_mode = if v["selector-mode"].is_badvalue() {
+                    SelectorMode::Drop
+                } else {
+                    let m = try!(v["selector-mode"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.selector-mode should be a string", name)));
+
+                    if m == "match" {
+                        SelectorMode::Match
+                    } else if m == "drop" {
+                        SelectorMode::Drop
+                    } else {
+                        return Err(format!("sinks.{}.selector-mode should be 'match' or 'drop'",
+                                           name)
+                            .into());
+                    }
+                };
+
+                let mut labels = HashMap::new();
+                if !v["labels"].is_badvalue() {
+                    let values = try!(v["labels"]
+                        .as_hash()
+                        .ok_or(format!("sinks.{}.labels should be a map", name)));
+                    for (k, v) in values {
+                        let label = try!(k.as_str()
+                            .ok_or(format!("sinks.{}.labels keys should be a string", name)));
+                        let value = try!(v.as_str()
+                            .ok_or(format!("sinks.{}.labels.{} value should be a string",
+                                           name,
+                                           label)));
+                        labels.insert(String::from(label), String::from(value));
+                    }
+                }
+
+                let ttl = if v["ttl"].is_badvalue() {
+                    3600
+                } else {
+                    let ttl = try!(v["ttl"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.ttl should be a number", name)));
+                    try!(cast::u64(ttl)
+                        .map_err(|_| format!("sinks.{}.ttl should be a positive number", name)))
+                };
+
+                let size = if v["size"].is_badvalue() {
+                    1073741824
+                } else {
+                    let size = try!(v["size"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.size should be a number", name)));
+                    try!(cast::u64(size)
+                        .map_err(|_| format!("sinks.{}.size should be a positive number", name)))
+                };
+
+                let retry = if v["retry"].is_badvalue() {
+                    0
+                } else {
+                    let retry = try!(v["retry"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.retry should be a number", name)));
+                    try!(cast::u64(retry)
+                        .map_err(|_| format!("sinks.{}.retry should be a positive number", name)))
+                };
+
+                let retry_delay = if v["retry-delay"].is_badvalue() {
+                    1000
+                } else {
+                    let retry_delay = try!(v["retry-delay"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.retry-delay should be a number", name)));
+                    try!(cast::u64(retry_delay)
+                        .map_err(|_| format!("sinks.{}.retry-delay should be a positive number",
+                                             name)))
+                };
+
+                let max_push_size = if v["max-push-size"].is_badvalue() {
+                    None
+                } else {
+                    let max_push_size = try!(v["max-push-size"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.max-push-size should be a number", name)));
+                    Some(try!(cast::u64(max_push_size)
+                        .map_err(|_| format!("sinks.{}.max-push-size should be a positive number",
+                                             name))))
+                };
+
+                let keep_alive = if v["keep-alive"].is_badvalue() {
+                    true
+                } else {
+                    try!(v["keep-alive"]
+                        .as_bool()
+                        .ok_or(format!("sinks.{}.keep-alive should be a boolean", name)))
+                };
+
+                let parallelism = if v["parallelism"].is_badvalue() {
+                    1
+                } else {
+                    let parallelism = try!(v["parallelism"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.parallelism should be a number", name)));
+                    let parallelism = try!(cast::u64(parallelism)
+                        .map_err(|_| format!("sinks.{}.parallelism should be a positive number",
+                                             name)));
+                    if parallelism == 0 {
+                        return Err(format!("sinks.{}.parallelism should be greater than 0", name)
+                            .into());
+                    }
+                    parallelism
+                };
+
+                let path = if v["path"].is_badvalue() {
+                    None
+                } else {
+                    Some(try!(v["path"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.path should be a string", name))))
+                };
+                let urls = match format {
+                    SinkFormat::Warp10 => {
+                        try!(urls.iter()
+                            .map(|url| sink_url(url, path, "/api/v0/update", name))
+                            .collect::<Result<Vec<String>, ConfigError>>())
+                    }
+                    SinkFormat::Influx if path.is_some() => {
+                        try!(urls.iter()
+                            .map(|url| sink_url(url, path, "", name))
+                            .collect::<Result<Vec<String>, ConfigError>>())
+                    }
+                    _ => urls,
+                };
+
+                let mut attributes = HashMap::new();
+                if !v["attributes"].is_badvalue() {
+                    if format != SinkFormat::Warp10 {
+                        return Err(format!("sinks.{}.attributes requires the warp10 format", name)
+                            .into());
+                    }
+                    let values = try!(v["attributes"]
+                        .as_hash()
+                        .ok_or(format!("sinks.{}.attributes should be a map", name)));
+                    for (k, v) in values {
+                        let attribute = try!(k.as_str()
+                            .ok_or(format!("sinks.{}.attributes keys should be a string", name)));
+                        let value = try!(v.as_str()
+                            .ok_or(format!("sinks.{}.attributes.{} value should be a string",
+                                           name,
+                                           attribute)));
+                        attributes.insert(String::from(attribute), String::from(value));
+                    }
+                }
+                let meta_path = if v["meta-path"].is_badvalue() {
+                    None
+                } else {
+                    Some(try!(v["meta-path"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.meta-path should be a string", name))))
+                };
+                let meta_urls = if attributes.is_empty() {
+                    Vec::new()
+                } else {
+                    let meta_path = meta_path.unwrap_or("/api/v0/meta");
+                    try!(urls.iter()
+                        .map(|url| sink_url(url, Some(meta_path), "", name))
+                        .collect::<Result<Vec<String>, ConfigError>>())
+                };
+
+                // Fall back to proxy environment variables, unless every url is excluded
+                let proxy = if v["proxy"].is_badvalue() {
+                    let https = urls.iter().any(|url| url.starts_with("https:"));
+                    let vars = if https {
+                        ["https_proxy", "HTTPS_PROXY"]
+                    } else {
+                        ["http_proxy", "HTTP_PROXY"]
+                    };
+                    let excluded = urls.iter().all(|url| {
+                        hyper::Url::parse(url)
+                            .ok()
+                            .and_then(|url| url.host_str().map(no_proxy))
+                            .unwrap_or(false)
+                    });
+                    if excluded {
+                        None
+                    } else {
+                        vars.iter().filter_map(|var| env::var(var).ok()).find(|v| !v.is_empty())
+                    }
+                } else {
+                    Some(String::from(try!(v["proxy"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.proxy should be a string", name)))))
+                };
+                if let Some(ref proxy) = proxy {
+                    let url = try!(hyper::Url::parse(proxy)
+                        .map_err(|err| format!("sinks.{}.proxy is invalid: {}", name, err)));
+                    if url.host_str().is_none() {
+                        return Err(format!("sinks.{}.proxy should have a host", name).into());
+                    }
+                }
+
+                let mut headers = HashMap::new();
+                if !v["headers"].is_badvalue() {
+                    let values = try!(v["headers"]
+                        .as_hash()
+                        .ok_or(format!("sinks.{}.headers should be a map", name)));
+                    for (k, v) in values {
+                        let header = try!(k.as_str()
+                            .ok_or(format!("sinks.{}.headers keys should be a string", name)));
+                        if !is_header_name(header) {
+                            return Err(format!("sinks.{}.headers.{} is not a valid header name",
+                                               name,
+                                               header)
+                                .into());
+                        }
+                        let value = try!(v.as_str()
+                            .ok_or(format!("sinks.{}.headers.{} value should be a string",
+                                           name,
+                                           header)));
+                        headers.insert(String::from(header), String::from(value));
+                    }
+                }
+
+                let user_agent = if v["user-agent"].is_badvalue() {
+                    None
+                } else {
+                    Some(String::from(try!(v["user-agent"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.user-agent should be a string", name)))))
+                };
+
+                let request_compression = if v["request-compression"].is_badvalue() {
+                    Compression::None
+                } else {
+                    let compression = try!(v["request-compression"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.request-compression should be a string",
+                                       name)));
+                    if compression == "none" {
+                        Compression::None
+                    } else if compression == "gzip" {
+                        Compression::Gzip
+                    } else {
+                        return Err(format!("sinks.{}.request-compression should be 'none' or \
+                                            'gzip'",
+                                           name)
+                            .into());
+                    }
+                };
+                if format == SinkFormat::Graphite && request_compression != Compression::None {
+                    return Err(format!("sinks.{} graphite format does not support \
+                                        request-compression",
+                                       name)
+                        .into());
+                }
+
+                let max_rate = if v["max-rate"].is_badvalue() {
+                    None
+                } else {
+                    let max_rate = try!(v["max-rate"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.max-rate should be a number", name)));
+                    if max_rate <= 0 {
+                        return Err(format!("sinks.{}.max-rate should be a positive number", name)
+                            .into());
+                    }
+                    Some(max_rate as u64)
+                };
+
+                let connect_timeout = if v["connect-timeout"].is_badvalue() {
+                    10000
+                } else {
+                    let connect_timeout = try!(v["connect-timeout"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.connect-timeout should be a number", name)));
+                    if connect_timeout <= 0 {
+                        return Err(format!("sinks.{}.connect-timeout should be a positive number",
+                                           name)
+                            .into());
+                    }
+                    connect_timeout as u64
+                };
+
+                let request_timeout = if v["request-timeout"].is_badvalue() {
+                    None
+                } else {
+                    let request_timeout = try!(v["request-timeout"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.request-timeout should be a number", name)));
+                    if request_timeout <= 0 {
+                        return Err(format!("sinks.{}.request-timeout should be a positive number",
+                                           name)
+                            .into());
+                    }
+                    Some(request_timeout as u64)
+                };
+
+                let http_version = if v["http-version"].is_badvalue() {
+                    HttpVersion::Http1
+                } else {
+                    let http_version = try!(v["http-version"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.http-version should be a string", name)));
+                    if http_version == "http1" {
+                        HttpVersion::Http1
+                    } else if http_version == "http2" {
+                        HttpVersion::Http2
+                    } else {
+                        return Err(format!("sinks.{}.http-version should be 'http1' or 'http2'",
+                                           name)
+                            .into());
+                    }
+                };
+
+                let label_selector = if v["label-selector"].is_badvalue() {
+                    None
+                } else {
+                    let selector = &v["label-selector"];
+                    let label = try!(selector["label"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.label-selector.label should be a string", name)));
+                    let regex = try!(selector["regex"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.label-selector.regex should be a string", name)));
+                    Some(LabelSelector {
+                        label: String::from(label),
+                        regex: try!(regex::Regex::new(regex)
+                            .map_err(|err| {
+                                format!("sinks.{}.label-selector.regex is invalid: {}", name, err)
+                            })),
+                    })
+                };
+
+                let preserve_order = if v["preserve-order"].is_badvalue() {
+                    false
+                } else {
+                    try!(v["preserve-order"]
+                        .as_bool()
+                        .ok_or(format!("sinks.{}.preserve-order should be a boolean", name)))
+                };
+
+                let coalesce = if v["coalesce"].is_badvalue() {
+                    true
+                } else {
+                    try!(v["coalesce"]
+                        .as_bool()
+                        .ok_or(format!("sinks.{}.coalesce should be a boolean", name)))
+                };
+
+                let coalesce_max_bytes = if v["coalesce-max-bytes"].is_badvalue() {
+                    None
+                } else {
+                    let coalesce_max_bytes = try!(v["coalesce-max-bytes"]
+                        .as_i64()
+                        .ok_or(format!("sinks.{}.coalesce-max-bytes should be a number", name)));
+                    if coalesce_max_bytes <= 0 {
+                        return Err(format!("sinks.{}.coalesce-max-bytes should be a positive \
+                                            number",
+                                           name)
+                            .into());
+                    }
+                    Some(coalesce_max_bytes as u64)
+                };
+
+                let class_prefix = if v["class-prefix"].is_badvalue() {
+                    None
+                } else {
+                    let class_prefix = try!(v["class-prefix"]
+                        .as_str()
+                        .ok_or(format!("sinks.{}.class-prefix should be a string", name)));
+                    if class_prefix.is_empty() ||
+                       class_prefix.contains(|c: char| c.is_whitespace() || c == '{' || c == '}') {
+                        return Err(format!("sinks.{}.class-prefix is invalid", name).into());
+                    }
+                    Some(String::from(class_prefix))
+                };
+
+                let validate = if v["validate"].is_badvalue() {
+                    false
+                } else {
+                    try!(v["validate"]
+                        .as_bool()
+                        .ok_or(format!("sinks.{}.validate should be a boolean", name)))
+                };
+
+                // Names are used for threads and files, a later definition overrides
+                config.sinks.retain(|sink| sink.name != name);
+                config.sinks.push(Sink {
+                    name: String::from(name),
+                    urls: urls,
+                    token: token,
+                    token_file: token_file,
+                    token_header: String::from(token_header),
+                    selector: selector,
+                    selector_mode: selector_mode,
+                    labels: labels,
+                    ttl: ttl,
+                    size: size,
+                    retry: retry,
+                    retry_delay: retry_delay,
+                    max_push_size: max_push_size,
+                    keep_alive: keep_alive,
+                    parallelism: parallelism,
+                    format: format,
+                    meta_urls: meta_urls,
+                    attributes: attributes,
+                    proxy: proxy,
+                    headers: headers,
+                    user_agent: user_agent,
+                    request_compression: request_compression,
+                    max_rate: max_rate,
+                    connect_timeout: connect_timeout,
+                    request_timeout: request_timeout,
+                    http_version: http_version,
+                    label_selector: label_selector,
+                    preserve_order: preserve_order,
+                    coalesce: coalesce,
+                    coalesce_max_bytes: coalesce_max_bytes,
+                    class_prefix: class_prefix,
+                    validate: validate,
+                    sink_type: sink_type,
+                })
+            }
+        }
+
+        if !doc["labels"].is_badvalue() {
+            let labels = try!(doc["labels"].as_hash().ok_or("labels should be a map"));
+            for (k, v) in labels {
+                let name = try!(k.as_str().ok_or("labels keys should be a string"));
+                let value = try!(v.as_str()
+                    .ok_or(format!("labels.{} value should be a string", name)));
+                config.labels.insert(String::from(name), String::from(value));
+            }
+        }
+
+        if !doc["parameters"].is_badvalue() {
+            if !doc["parameters"]["source-dir"].is_badvalue() {
+                let source_dir = try!(doc["parameters"]["source-dir"]
+                    .as_str()
+                    .ok_or(format!("parameters.source-dir should be a string")));
+                config.parameters.source_dir = String::from(source_dir);
+            }
+
+            if !doc["parameters"]["source-extension"].is_badvalue() {
+                let source_extension = try!(doc["parameters"]["source-extension"]
+                    .as_str()
+                    .ok_or(format!("parameters.source-extension should be a string")));
+                if source_extension.is_empty() ||
+                   source_extension.contains(|c: char| c == '/' || c == '\\' || c == '.') {
+                    return Err(format!("parameters.source-extension should not be empty or \
+                                        contain path separators or dots")
+                        .into());
+                }
+                if source_extension == "tmp" {
+                    return Err(format!("parameters.source-extension should not be 'tmp'").into());
+                }
+                config.parameters.source_extension = String::from(source_extension);
+            }
+
+            if !doc["parameters"]["sink-dir"].is_badvalue() {
+                let sink_dir = try!(doc["parameters"]["sink-dir"]
+                    .as_str()
+                    .ok_or(format!("parameters.sink-dir should be a string")));
+                config.parameters.sink_dir = String::from(sink_dir);
+            }
+
+            if !doc["parameters"]["scan-period"].is_badvalue() {
+                let scan_period = try!(doc["parameters"]["scan-period"]
+                    .as_i64()
+                    .ok_or(format!("parameters.scan-period should be a number")));
+                let scan_period = try!(cast::u64(scan_period)
+                    .map_err(|_| format!("parameters.scan-period is invalid")));
+                config.parameters.scan_period = scan_period;
+            }
+
+            if !doc["parameters"]["batch-size"].is_badvalue() {
+                let batch_size = try!(doc["parameters"]["batch-size"]
+                    .as_i64()
+                    .ok_or(format!("parameters.batch-size should be a number")));
+                let batch_size = try!(cast::u64(batch_size)
+                    .map_err(|_| format!("parameters.batch-size is invalid")));
+                config.parameters.batch_size = batch_size;
+            }
+
+            if !doc["parameters"]["batch-count"].is_badvalue() {
+                let batch_count = try!(doc["parameters"]["batch-count"]
                     .as_i64()
                     .ok_or(format!("parameters.batch-count should be a number")));
                 let batch_count = try!(cast::u64(batch_count)
@@ -356,6 +1838,15 @@ fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), Co
                 config.parameters.batch_count = batch_count;
             }
 
+            if !doc["parameters"]["batch-lines"].is_badvalue() {
+                let batch_lines = try!(doc["parameters"]["batch-lines"]
+                    .as_i64()
+                    .ok_or(format!("parameters.batch-lines should be a number")));
+                let batch_lines = try!(cast::u64(batch_lines)
+                    .map_err(|_| format!("parameters.batch-lines is invalid")));
+                config.parameters.batch_lines = Some(batch_lines);
+            }
+
             if !doc["parameters"]["log-file"].is_badvalue() {
                 let log_file = try!(doc["parameters"]["log-file"]
                     .as_str()
@@ -382,8 +1873,786 @@ fn load_path<P: AsRef<Path>>(file_path: P, config: &mut Config) -> Result<(), Co
                     .map_err(|_| format!("parameters.timeout is invalid")));
                 config.parameters.timeout = timeout;
             }
+
+            if !doc["parameters"]["shutdown-timeout"].is_badvalue() {
+                let shutdown_timeout = try!(doc["parameters"]["shutdown-timeout"]
+                    .as_i64()
+                    .ok_or(format!("parameters.shutdown-timeout should be a number")));
+                let shutdown_timeout = try!(cast::u64(shutdown_timeout)
+                    .map_err(|_| format!("parameters.shutdown-timeout is invalid")));
+                config.parameters.shutdown_timeout = shutdown_timeout;
+            }
+
+            if !doc["parameters"]["sink-dir-max-size"].is_badvalue() {
+                let sink_dir_max_size = try!(doc["parameters"]["sink-dir-max-size"]
+                    .as_i64()
+                    .ok_or(format!("parameters.sink-dir-max-size should be a number")));
+                let sink_dir_max_size = try!(cast::u64(sink_dir_max_size)
+                    .map_err(|_| format!("parameters.sink-dir-max-size is invalid")));
+                config.parameters.sink_dir_max_size = Some(sink_dir_max_size);
+            }
+
+            if !doc["parameters"]["compression"].is_badvalue() {
+                let compression = try!(doc["parameters"]["compression"]
+                    .as_str()
+                    .ok_or(format!("parameters.compression should be a string")));
+                config.parameters.compression = if compression == "none" {
+                    Compression::None
+                } else if compression == "gzip" {
+                    Compression::Gzip
+                } else {
+                    return Err(format!("parameters.compression should be 'none' or 'gzip'").into());
+                };
+            }
+
+            if !doc["parameters"]["self-metrics"].is_badvalue() {
+                let self_metrics = try!(doc["parameters"]["self-metrics"]
+                    .as_bool()
+                    .ok_or(format!("parameters.self-metrics should be a boolean")));
+                config.parameters.self_metrics = self_metrics;
+            }
+
+            if !doc["parameters"]["listen"].is_badvalue() {
+                let listen = try!(doc["parameters"]["listen"]
+                    .as_str()
+                    .ok_or(format!("parameters.listen should be a string")));
+                try!(listen.parse::<SocketAddr>()
+                    .map_err(|_| format!("parameters.listen should be an address")));
+                config.parameters.listen = Some(String::from(listen));
+            }
+
+            if !doc["parameters"]["metric-mapper"].is_badvalue() {
+                let metric_mapper = try!(doc["parameters"]["metric-mapper"]
+                    .as_str()
+                    .ok_or(format!("parameters.metric-mapper should be a string")));
+                config.parameters.metric_mapper = if metric_mapper == "none" {
+                    MetricMapper::None
+                } else if metric_mapper == "dots" {
+                    MetricMapper::Dots
+                } else {
+                    return Err(format!("parameters.metric-mapper should be 'none' or 'dots'")
+                        .into());
+                };
+            }
+
+            if !doc["parameters"]["drop-nan"].is_badvalue() {
+                let drop_nan = try!(doc["parameters"]["drop-nan"]
+                    .as_bool()
+                    .ok_or(format!("parameters.drop-nan should be a boolean")));
+                config.parameters.drop_nan = drop_nan;
+            }
+
+            if !doc["parameters"]["dead-letter-dir"].is_badvalue() {
+                let dead_letter_dir = try!(doc["parameters"]["dead-letter-dir"]
+                    .as_str()
+                    .ok_or(format!("parameters.dead-letter-dir should be a string")));
+                config.parameters.dead_letter_dir = Some(String::from(dead_letter_dir));
+            }
+
+            if !doc["parameters"]["dead-letter-status"].is_badvalue() {
+                let values = try!(doc["parameters"]["dead-letter-status"]
+                    .as_vec()
+                    .ok_or(format!("parameters.dead-letter-status should be an array")));
+                let mut dead_letter_status = Vec::new();
+                for v in values {
+                    let status = try!(v.as_i64()
+                        .ok_or(format!("parameters.dead-letter-status should contain numbers")));
+                    let status = try!(cast::u16(status)
+                        .map_err(|_| format!("parameters.dead-letter-status is invalid")));
+                    dead_letter_status.push(status);
+                }
+                config.parameters.dead_letter_status = dead_letter_status;
+            }
+
+            if !doc["parameters"]["scrape-jitter"].is_badvalue() {
+                let scrape_jitter = try!(doc["parameters"]["scrape-jitter"]
+                    .as_i64()
+                    .ok_or(format!("parameters.scrape-jitter should be a number")));
+                let scrape_jitter = try!(cast::u64(scrape_jitter)
+                    .map_err(|_| format!("parameters.scrape-jitter is invalid")));
+                config.parameters.scrape_jitter = scrape_jitter;
+            }
+
+            if !doc["parameters"]["jitter-seed"].is_badvalue() {
+                let jitter_seed = try!(doc["parameters"]["jitter-seed"]
+                    .as_i64()
+                    .ok_or(format!("parameters.jitter-seed should be a number")));
+                let jitter_seed = try!(cast::u64(jitter_seed)
+                    .map_err(|_| format!("parameters.jitter-seed is invalid")));
+                config.parameters.jitter_seed = Some(jitter_seed);
+            }
+
+            if !doc["parameters"]["log-format"].is_badvalue() {
+                let log_format = try!(doc["parameters"]["log-format"]
+                    .as_str()
+                    .ok_or(format!("parameters.log-format should be a string")));
+                config.parameters.log_format = if log_format == "term" {
+                    LogFormat::Term
+                } else if log_format == "json" {
+                    LogFormat::Json
+                } else {
+                    return Err(format!("parameters.log-format should be 'term' or 'json'").into());
+                };
+            }
+
+            if !doc["parameters"]["log-file-max-size"].is_badvalue() {
+                let log_file_max_size = try!(doc["parameters"]["log-file-max-size"]
+                    .as_i64()
+                    .ok_or(format!("parameters.log-file-max-size should be a number")));
+                let log_file_max_size = try!(cast::u64(log_file_max_size)
+                    .map_err(|_| format!("parameters.log-file-max-size is invalid")));
+                config.parameters.log_file_max_size = Some(log_file_max_size);
+            }
+
+            if !doc["parameters"]["log-file-keep"].is_badvalue() {
+                let log_file_keep = try!(doc["parameters"]["log-file-keep"]
+                    .as_i64()
+                    .ok_or(format!("parameters.log-file-keep should be a number")));
+                let log_file_keep = try!(cast::u64(log_file_keep)
+                    .map_err(|_| format!("parameters.log-file-keep is invalid")));
+                config.parameters.log_file_keep = log_file_keep;
+            }
+
+            if !doc["parameters"]["sanitize-le"].is_badvalue() {
+                let sanitize_le = try!(doc["parameters"]["sanitize-le"]
+                    .as_bool()
+                    .ok_or(format!("parameters.sanitize-le should be a boolean")));
+                config.parameters.sanitize_le = sanitize_le;
+            }
+
+            if !doc["parameters"]["timestamp-unit"].is_badvalue() {
+                let timestamp_unit = try!(doc["parameters"]["timestamp-unit"]
+                    .as_str()
+                    .ok_or(format!("parameters.timestamp-unit should be a string")));
+                config.parameters.timestamp_unit = if timestamp_unit == "ms" {
+                    TimestampUnit::Millis
+                } else if timestamp_unit == "us" {
+                    TimestampUnit::Micros
+                } else if timestamp_unit == "ns" {
+                    TimestampUnit::Nanos
+                } else {
+                    return Err(format!("parameters.timestamp-unit should be 'ms', 'us' or 'ns'")
+                        .into());
+                };
+            }
+
+            if !doc["parameters"]["dedup"].is_badvalue() {
+                let dedup = try!(doc["parameters"]["dedup"]
+                    .as_bool()
+                    .ok_or(format!("parameters.dedup should be a boolean")));
+                config.parameters.dedup = dedup;
+            }
+
+            if !doc["parameters"]["router-min-rest"].is_badvalue() {
+                let router_min_rest = try!(doc["parameters"]["router-min-rest"]
+                    .as_i64()
+                    .ok_or(format!("parameters.router-min-rest should be a number")));
+                let router_min_rest = try!(cast::u64(router_min_rest)
+                    .map_err(|_| format!("parameters.router-min-rest is invalid")));
+                config.parameters.router_min_rest = router_min_rest;
+            }
+
+            if !doc["parameters"]["router-rest-time"].is_badvalue() {
+                let router_rest_time = try!(doc["parameters"]["router-rest-time"]
+                    .as_i64()
+                    .ok_or(format!("parameters.router-rest-time should be a number")));
+                let router_rest_time = try!(cast::u64(router_rest_time)
+                    .map_err(|_| format!("parameters.router-rest-time is invalid")));
+                if router_rest_time == 0 {
+                    return Err(format!("parameters.router-rest-time should be greater than 0")
+                        .into());
+                }
+                config.parameters.router_rest_time = router_rest_time;
+            }
+
+            if !doc["parameters"]["router-read-parallelism"].is_badvalue() {
+                let router_read_parallelism = try!(doc["parameters"]["router-read-parallelism"]
+                    .as_i64()
+                    .ok_or(format!("parameters.router-read-parallelism should be a number")));
+                let router_read_parallelism = try!(cast::u64(router_read_parallelism)
+                    .map_err(|_| format!("parameters.router-read-parallelism is invalid")));
+                if router_read_parallelism == 0 {
+                    return Err(format!("parameters.router-read-parallelism should be greater \
+                                        than 0")
+                        .into());
+                }
+                config.parameters.router_read_parallelism = router_read_parallelism;
+            }
+
+            if !doc["parameters"]["user-agent"].is_badvalue() {
+                let user_agent = try!(doc["parameters"]["user-agent"]
+                    .as_str()
+                    .ok_or(format!("parameters.user-agent should be a string")));
+                config.parameters.user_agent = String::from(user_agent);
+            }
+
+            if !doc["parameters"]["fsync"].is_badvalue() {
+                let fsync = try!(doc["parameters"]["fsync"]
+                    .as_str()
+                    .ok_or(format!("parameters.fsync should be a string")));
+                config.parameters.fsync = if fsync == "none" {
+                    Fsync::None
+                } else if fsync == "data" {
+                    Fsync::Data
+                } else if fsync == "full" {
+                    Fsync::Full
+                } else {
+                    return Err(format!("parameters.fsync should be 'none', 'data' or 'full'")
+                        .into());
+                };
+            }
+
+            if !doc["parameters"]["scrape-concurrency"].is_badvalue() {
+                let scrape_concurrency = try!(doc["parameters"]["scrape-concurrency"]
+                    .as_i64()
+                    .ok_or(format!("parameters.scrape-concurrency should be a number")));
+                let scrape_concurrency = try!(cast::u64(scrape_concurrency)
+                    .map_err(|_| format!("parameters.scrape-concurrency is invalid")));
+                if scrape_concurrency == 0 {
+                    return Err(format!("parameters.scrape-concurrency should be greater than 0")
+                        .into());
+                }
+                config.parameters.scrape_concurrency = Some(scrape_concurrency);
+            }
+
+            if !doc["parameters"]["worker-threads"].is_badvalue() {
+                let worker_threads = try!(doc["parameters"]["worker-threads"]
+                    .as_i64()
+                    .ok_or(format!("parameters.worker-threads should be a number")));
+                let worker_threads = try!(cast::u64(worker_threads)
+                    .map_err(|_| format!("parameters.worker-threads is invalid")));
+                if worker_threads == 0 {
+                    return Err(format!("parameters.worker-threads should be greater than 0")
+                        .into());
+                }
+                config.parameters.worker_threads = Some(worker_threads);
+            }
+
+            if !doc["parameters"]["encode-labels"].is_badvalue() {
+                let encode_labels = try!(doc["parameters"]["encode-labels"]
+                    .as_bool()
+                    .ok_or(format!("parameters.encode-labels should be a boolean")));
+                config.parameters.encode_labels = encode_labels;
+            }
+
+            if !doc["parameters"]["corrupt-dir"].is_badvalue() {
+                let corrupt_dir = try!(doc["parameters"]["corrupt-dir"]
+                    .as_str()
+                    .ok_or(format!("parameters.corrupt-dir should be a string")));
+                config.parameters.corrupt_dir = Some(String::from(corrupt_dir));
+            }
+
+            if !doc["parameters"]["validation"].is_badvalue() {
+                let validation = try!(doc["parameters"]["validation"]
+                    .as_str()
+                    .ok_or(format!("parameters.validation should be a string")));
+                config.parameters.validation = if validation == "off" {
+                    Validation::Off
+                } else if validation == "lenient" {
+                    Validation::Lenient
+                } else if validation == "strict" {
+                    Validation::Strict
+                } else {
+                    return Err(format!("parameters.validation should be 'off', 'lenient' or \
+                                        'strict'")
+                        .into());
+                };
+            }
+
+            if !doc["parameters"]["tenant-label"].is_badvalue() {
+                let tenant_label = try!(doc["parameters"]["tenant-label"]
+                    .as_str()
+                    .ok_or(format!("parameters.tenant-label should be a string")));
+                config.parameters.tenant_label = Some(String::from(tenant_label));
+            }
+
+            if !doc["parameters"]["tenants"].is_badvalue() {
+                let tenants = try!(doc["parameters"]["tenants"]
+                    .as_hash()
+                    .ok_or(format!("parameters.tenants should be a map")));
+                for (k, v) in tenants {
+                    let tenant = try!(k.as_str()
+                        .ok_or(format!("parameters.tenants keys should be a string")));
+                    let sink = try!(v.as_str()
+                        .ok_or(format!("parameters.tenants.{} value should be a string", tenant)));
+                    config.parameters.tenants.insert(String::from(tenant), String::from(sink));
+                }
+            }
+
+            if !doc["parameters"]["tenant-default"].is_badvalue() {
+                let tenant_default = try!(doc["parameters"]["tenant-default"]
+                    .as_str()
+                    .ok_or(format!("parameters.tenant-default should be a string")));
+                config.parameters.tenant_default = Some(String::from(tenant_default));
+            }
+
+            if !doc["parameters"]["dns-cache-ttl"].is_badvalue() {
+                let dns_cache_ttl = try!(doc["parameters"]["dns-cache-ttl"]
+                    .as_i64()
+                    .ok_or(format!("parameters.dns-cache-ttl should be a number")));
+                let dns_cache_ttl = try!(cast::u64(dns_cache_ttl)
+                    .map_err(|_| format!("parameters.dns-cache-ttl is invalid")));
+                config.parameters.dns_cache_ttl = Some(dns_cache_ttl);
+            }
+
+            if !doc["parameters"]["cleanup-orphans"].is_badvalue() {
+                let cleanup_orphans = try!(doc["parameters"]["cleanup-orphans"]
+                    .as_bool()
+                    .ok_or(format!("parameters.cleanup-orphans should be a boolean")));
+                config.parameters.cleanup_orphans = cleanup_orphans;
+            }
+
+            if !doc["parameters"]["sort-labels"].is_badvalue() {
+                let sort_labels = try!(doc["parameters"]["sort-labels"]
+                    .as_bool()
+                    .ok_or(format!("parameters.sort-labels should be a boolean")));
+                config.parameters.sort_labels = sort_labels;
+            }
+
+            if !doc["parameters"]["restart-on-panic"].is_badvalue() {
+                let restart_on_panic = try!(doc["parameters"]["restart-on-panic"]
+                    .as_bool()
+                    .ok_or(format!("parameters.restart-on-panic should be a boolean")));
+                config.parameters.restart_on_panic = restart_on_panic;
+            }
+
+            if !doc["parameters"]["log-levels"].is_badvalue() {
+                let log_levels = try!(doc["parameters"]["log-levels"]
+                    .as_hash()
+                    .ok_or(format!("parameters.log-levels should be a map")));
+                for (k, v) in log_levels {
+                    let module = try!(k.as_str()
+                        .ok_or(format!("parameters.log-levels keys should be a string")));
+                    if !LOG_MODULES.contains(&module) {
+                        return Err(format!("parameters.log-levels.{} is not a module, should be \
+                                            one of {}",
+                                           module,
+                                           LOG_MODULES.join(", "))
+                            .into());
+                    }
+                    let log_level = try!(v.as_i64()
+                        .ok_or(format!("parameters.log-levels.{} should be a number", module)));
+                    let log_level = try!(cast::u64(log_level)
+                        .map_err(|_| format!("parameters.log-levels.{} is invalid", module)));
+                    let log_level = try!(slog::Level::from_usize(log_level as usize)
+                        .ok_or(format!("parameters.log-levels.{} is invalid", module)));
+                    config.parameters.log_levels.insert(String::from(module), log_level);
+                }
+            }
+
+            if !doc["parameters"]["labels-file"].is_badvalue() {
+                let labels_file = try!(doc["parameters"]["labels-file"]
+                    .as_str()
+                    .ok_or(format!("parameters.labels-file should be a string")));
+                config.parameters.labels_file = Some(String::from(labels_file));
+            }
+
+            if !doc["parameters"]["labels-file-period"].is_badvalue() {
+                let labels_file_period = try!(doc["parameters"]["labels-file-period"]
+                    .as_i64()
+                    .ok_or(format!("parameters.labels-file-period should be a number")));
+                let labels_file_period = try!(cast::u64(labels_file_period)
+                    .map_err(|_| format!("parameters.labels-file-period is invalid")));
+                config.parameters.labels_file_period = labels_file_period;
+            }
+
+            if !doc["parameters"]["file-mode"].is_badvalue() {
+                let file_mode = try!(mode(&doc["parameters"]["file-mode"], "file-mode"));
+                config.parameters.file_mode = Some(file_mode);
+            }
+
+            if !doc["parameters"]["dir-mode"].is_badvalue() {
+                let dir_mode = try!(mode(&doc["parameters"]["dir-mode"], "dir-mode"));
+                config.parameters.dir_mode = Some(dir_mode);
+            }
         }
     }
 
     Ok(())
 }
+
+/// Load a labels file, a YAML map of labels.
+pub fn load_labels(file_path: &str) -> Result<HashMap<String, String>, ConfigError> {
+    let mut file = try!(File::open(file_path));
+    let mut contents = String::new();
+    try!(file.read_to_string(&mut contents));
+
+    let mut labels = HashMap::new();
+    for doc in try!(YamlLoader::load_from_str(&contents)) {
+        let values = try!(doc.as_hash().ok_or("labels file should be a map"));
+        for (k, v) in values {
+            let name = try!(k.as_str().ok_or("labels keys should be a string"));
+            let value = try!(v.as_str().ok_or(format!("labels.{} value should be a string", name)));
+            labels.insert(String::from(name), String::from(value));
+        }
+    }
+
+    Ok(labels)
+}
+
+/// Parse an octal permission mode, given as a quoted string such as "0640".
+fn mode(value: &Yaml, key: &str) -> Result<u32, ConfigError> {
+    // Integers are already decimal, YAML reads 0600 as 600 and 0o600 as 384
+    let digits = try!(value.as_str()
+        .ok_or(format!("parameters.{} should be a quoted octal mode string", key)));
+    let mode = try!(u32::from_str_radix(digits.trim_left_matches("0o"), 8)
+        .map_err(|_| format!("parameters.{} should be an octal mode", key)));
+    if mode > 0o7777 {
+        return Err(format!("parameters.{} should be an octal mode", key).into());
+    }
+
+    Ok(mode)
+}
+
+/// Check if a host is excluded from proxying by the NO_PROXY environment variable.
+/// Entries match the host and its subdomains, `*` matches every host.
+fn no_proxy(host: &str) -> bool {
+    let hosts = match ["no_proxy", "NO_PROXY"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|v| !v.is_empty()) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    hosts.split(',').map(|entry| entry.trim().trim_left_matches('.')).any(|entry| {
+        entry == "*" ||
+        (!entry.is_empty() &&
+         (host == entry || host.ends_with(&format!(".{}", entry))))
+    })
+}
+
+/// Check a string is a valid HTTP header name, a RFC 7230 token.
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty() &&
+    name.chars().all(|c| ((c as u32) < 128 && c.is_alphanumeric()) || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Expand environment variables in YAML string values.
+fn expand_env(value: Yaml) -> Result<Yaml, ConfigError> {
+    Ok(match value {
+        Yaml::String(v) => Yaml::String(try!(expand(&v))),
+        Yaml::Array(v) => Yaml::Array(try!(v.into_iter().map(expand_env).collect())),
+        Yaml::Hash(v) => {
+            let mut hash = Hash::new();
+            for (k, v) in v {
+                hash.insert(k, try!(expand_env(v)));
+            }
+            Yaml::Hash(hash)
+        }
+        v => v,
+    })
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` references in a string.
+/// `${source.name}` is kept as is.
+fn expand(value: &str) -> Result<String, ConfigError> {
+    let mut result = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let end = try!(rest[start..]
+            .find('}')
+            .ok_or(format!("unclosed variable reference in '{}'", value)));
+
+        // Source name is expanded by the router
+        if &rest[start..start + end + 1] == SOURCE_NAME {
+            result.push_str(SOURCE_NAME);
+            rest = &rest[start + end + 1..];
+            continue;
+        }
+
+        let mut parts = rest[start + 2..start + end].splitn(2, ":-");
+        let name = parts.next().unwrap_or("");
+        match (env::var(name), parts.next()) {
+            (Ok(v), _) => result.push_str(&v),
+            (Err(_), Some(default)) => result.push_str(default),
+            (Err(_), None) => {
+                return Err(format!("environment variable {} is not set", name).into())
+            }
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Convert a TOML value to YAML.
+fn toml_to_yaml(value: toml::Value) -> Yaml {
+    match value {
+        toml::Value::String(v) => Yaml::String(v),
+        toml::Value::Integer(v) => Yaml::Integer(v),
+        toml::Value::Float(v) => Yaml::Real(v.to_string()),
+        toml::Value::Boolean(v) => Yaml::Boolean(v),
+        toml::Value::Datetime(v) => Yaml::String(v.to_string()),
+        toml::Value::Array(v) => Yaml::Array(v.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(v) => {
+            Yaml::Hash(v.into_iter().map(|(k, v)| (Yaml::String(k), toml_to_yaml(v))).collect())
+        }
+    }
+}
+
+/// Get an optional TLS file path from a source.
+/// The file is opened to fail fast on misconfiguration.
+fn tls_file(source: &Yaml, name: &str, key: &str) -> Result<Option<String>, ConfigError> {
+    if source[key].is_badvalue() {
+        return Ok(None);
+    }
+
+    let path = try!(source[key]
+        .as_str()
+        .ok_or(format!("sources.{}.{} should be a string", name, key)));
+    try!(File::open(path)
+        .map_err(|err| format!("sources.{}.{} is unreadable: {}", name, key, err)));
+
+    Ok(Some(String::from(path)))
+}
+
+/// Build a sink url, path replace the url one, default is used when the url has no path.
+fn sink_url(url: &str,
+            path: Option<&str>,
+            default: &str,
+            name: &str)
+            -> Result<String, ConfigError> {
+    let mut parsed = try!(hyper::Url::parse(url)
+        .map_err(|err| format!("sinks.{}.url is invalid: {}", name, err)));
+    match path {
+        Some(path) => parsed.set_path(path),
+        None if parsed.path() == "/" && !default.is_empty() => parsed.set_path(default),
+        None => {}
+    }
+
+    Ok(String::from(parsed.as_str()))
+}
+
+/// Parse a list of regex matching whole metric classes, empty lists are ignored.
+fn class_set(source: &Yaml, name: &str, key: &str) -> Result<Option<regex::RegexSet>, ConfigError> {
+    if source[key].is_badvalue() {
+        return Ok(None);
+    }
+
+    let values = try!(source[key]
+        .as_vec()
+        .ok_or(format!("sources.{}.{} should be an array", name, key)));
+    let mut patterns = Vec::new();
+    for v in values {
+        let pattern = try!(v.as_str()
+            .ok_or(format!("sources.{}.{} should contain strings", name, key)));
+        patterns.push(format!("^(?:{})$", pattern));
+    }
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(try!(regex::RegexSet::new(&patterns))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Write a config file in a fresh scratch dir.
+    fn write_config(name: &str, file_name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("beamium-config-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(file_name);
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn yaml_and_toml_configs_are_equal() {
+        let yaml = write_config("yaml", "config.yaml", r#"
+sources:
+  node:
+    url: http://127.0.0.1:9100/metrics
+    period: 10000
+    format: prometheus
+sinks:
+  warp:
+    url: http://127.0.0.1:8080/api/v0/update
+    token: secret
+    selector: node_.*
+    ttl: 600
+labels:
+  host: test
+parameters:
+  scan-period: 500
+  batch-count: 10
+  compression: gzip
+  dedup: true
+"#);
+        let toml = write_config("toml", "config.toml", r#"
+[sources.node]
+url = "http://127.0.0.1:9100/metrics"
+period = 10000
+format = "prometheus"
+
+[sinks.warp]
+url = "http://127.0.0.1:8080/api/v0/update"
+token = "secret"
+selector = "node_.*"
+ttl = 600
+
+[labels]
+host = "test"
+
+[parameters]
+scan-period = 500
+batch-count = 10
+compression = "gzip"
+dedup = true
+"#);
+
+        let yaml = load_config(yaml.to_str().unwrap()).unwrap();
+        let toml = load_config(toml.to_str().unwrap()).unwrap();
+        assert_eq!(yaml.sources, toml.sources);
+        assert_eq!(yaml.sinks, toml.sinks);
+        assert_eq!(yaml.labels, toml.labels);
+        assert_eq!(yaml.parameters, toml.parameters);
+        assert_eq!(yaml.parameters.compression, Compression::Gzip);
+        assert_eq!(toml.sinks[0].ttl, 600);
+    }
+
+    #[test]
+    fn expand_present_variable() {
+        env::set_var("BEAMIUM_TEST_PRESENT", "secret");
+        assert_eq!(expand("token-${BEAMIUM_TEST_PRESENT}").unwrap(), "token-secret");
+    }
+
+    #[test]
+    fn expand_missing_variable() {
+        env::remove_var("BEAMIUM_TEST_MISSING");
+        let err = expand("${BEAMIUM_TEST_MISSING}").unwrap_err();
+        assert!(err.to_string().contains("BEAMIUM_TEST_MISSING"), "{}", err);
+    }
+
+    #[test]
+    fn expand_default_value() {
+        env::remove_var("BEAMIUM_TEST_DEFAULT");
+        assert_eq!(expand("${BEAMIUM_TEST_DEFAULT:-http://127.0.0.1}/api").unwrap(),
+                   "http://127.0.0.1/api");
+
+        env::set_var("BEAMIUM_TEST_DEFAULT", "http://warp");
+        assert_eq!(expand("${BEAMIUM_TEST_DEFAULT:-http://127.0.0.1}/api").unwrap(),
+                   "http://warp/api");
+    }
+
+    #[test]
+    fn ipv6_urls_and_listen() {
+        let path = write_config("ipv6", "config.yaml", r#"
+sources:
+  node:
+    url: http://[::1]:9100/metrics
+    period: 10000
+sinks:
+  warp:
+    url: http://[fe80::1]:8080/api/v0/update
+    token: secret
+parameters:
+  listen: "[::1]:9110"
+"#);
+
+        let config = load_config(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.sources[0].url, "http://[::1]:9100/metrics");
+        assert_eq!(config.sinks[0].urls, vec!["http://[fe80::1]:8080/api/v0/update"]);
+        assert_eq!(config.parameters.listen, Some(String::from("[::1]:9110")));
+    }
+
+    #[test]
+    fn duplicate_sink_in_config_dir() {
+        let first = write_config("dir-sinks", "10-first.yaml", r#"
+sinks:
+  warp:
+    url: http://127.0.0.1:8080/api/v0/update
+    token: first
+"#);
+        let dir = first.parent().unwrap();
+        File::create(dir.join("20-second.yaml"))
+            .unwrap()
+            .write_all(b"sinks:\n  warp:\n    url: http://127.0.0.1:8081/api/v0/update\n    \
+                         token: second\n")
+            .unwrap();
+
+        let err = load_config(dir.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("sinks.warp"), "{}", err);
+        assert!(err.contains("10-first.yaml") && err.contains("20-second.yaml"), "{}", err);
+    }
+
+    #[test]
+    fn later_config_file_overrides_sink() {
+        let first = write_config("override-first", "config.yaml", r#"
+sinks:
+  warp:
+    url: http://127.0.0.1:8080/api/v0/update
+    token: first
+"#);
+        let second = write_config("override-second", "config.yaml", r#"
+sinks:
+  warp:
+    url: http://127.0.0.1:8081/api/v0/update
+    token: second
+"#);
+
+        let mut config = load_config(first.to_str().unwrap()).unwrap();
+        load_path(&second, &mut config).unwrap();
+        assert_eq!(config.sinks.len(), 1);
+        assert_eq!(config.sinks[0].token, Some(String::from("second")));
+    }
+
+    #[test]
+    fn duplicate_source_in_config_dir() {
+        let first = write_config("dir-sources", "10-first.yaml", r#"
+sources:
+  node:
+    url: http://127.0.0.1:9100/metrics
+    period: 10000
+"#);
+        let dir = first.parent().unwrap();
+        File::create(dir.join("20-second.yaml"))
+            .unwrap()
+            .write_all(b"sources:\n  node:\n    url: http://127.0.0.1:9101/metrics\n    \
+                         period: 10000\n")
+            .unwrap();
+
+        let err = load_config(dir.to_str().unwrap()).unwrap_err().to_string();
+        assert!(err.contains("sources.node"), "{}", err);
+        assert!(err.contains("10-first.yaml") && err.contains("20-second.yaml"), "{}", err);
+    }
+
+    #[test]
+    fn sink_http_version() {
+        let path = write_config("http-version", "config.yaml", r#"
+sinks:
+  h1:
+    url: http://127.0.0.1/
+    token: a
+  h2:
+    url: http://127.0.0.1/
+    token: b
+    http-version: http2
+"#);
+        let config = load_config(path.to_str().unwrap()).unwrap();
+        let version = |name: &str| {
+            config.sinks.iter().find(|sink| sink.name == name).unwrap().http_version.clone()
+        };
+        assert_eq!(version("h1"), HttpVersion::Http1);
+        assert_eq!(version("h2"), HttpVersion::Http2);
+
+        let path = write_config("http-version-bad",
+                                "config.yaml",
+                                "sinks:\n  h3:\n    url: http://127.0.0.1/\n    token: c\n    \
+                                 http-version: http3\n");
+        assert!(load_config(path.to_str().unwrap()).is_err());
+    }
+}
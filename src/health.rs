@@ -0,0 +1,102 @@
+//! # Health module.
+//!
+//! The Health module track workers liveness and serve health checks and metrics over HTTP.
+use std::thread;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use std::collections::HashMap;
+use hyper;
+use hyper::server::{Server, Request, Response, Listening};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+
+use stats;
+
+lazy_static! {
+    /// Workers liveness, indexed by worker name.
+    static ref WORKERS: Mutex<HashMap<String, bool>> = Mutex::new(HashMap::new());
+}
+
+/// Set once the first route or push succeed.
+static READY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Worker liveness guard.
+/// The worker is flagged as dead if its thread panic.
+pub struct Guard {
+    name: String,
+}
+
+impl Guard {
+    /// Flag the worker as alive or dead, for workers recovering from panics by themselves.
+    pub fn alive(&self, alive: bool) {
+        WORKERS.lock().unwrap_or_else(|e| e.into_inner()).insert(self.name.clone(), alive);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut workers = WORKERS.lock().unwrap_or_else(|e| e.into_inner());
+        if thread::panicking() {
+            workers.insert(self.name.clone(), false);
+        } else {
+            workers.remove(&self.name);
+        }
+    }
+}
+
+/// Register a worker as alive until the returned guard is dropped.
+pub fn register(name: &str) -> Guard {
+    WORKERS.lock().unwrap().insert(String::from(name), true);
+    Guard { name: String::from(name) }
+}
+
+/// Flag beamium as ready.
+pub fn ready() {
+    READY.store(true, Ordering::Relaxed);
+}
+
+/// Serve health checks on the given address.
+pub fn listen(addr: &str) -> hyper::Result<Listening> {
+    let server = try!(Server::http(addr));
+    server.handle(handle)
+}
+
+/// Handle health check request.
+fn handle(req: Request, mut res: Response) {
+    let path = match req.uri {
+        RequestUri::AbsolutePath(ref p) => String::from(p.split('?').next().unwrap_or("")),
+        _ => String::new(),
+    };
+
+    if path == "/metrics" {
+        *res.status_mut() = StatusCode::Ok;
+        if let Err(err) = res.send(stats::prometheus().as_bytes()) {
+            warn!("health response fail: {}", err);
+        }
+        return;
+    }
+
+    let (status, body) = match path.as_str() {
+        "/healthz" => {
+            let workers = WORKERS.lock().unwrap_or_else(|e| e.into_inner());
+            if workers.values().all(|alive| *alive) {
+                (StatusCode::Ok, "ok")
+            } else {
+                (StatusCode::ServiceUnavailable, "dead worker")
+            }
+        }
+        "/ready" => {
+            if READY.load(Ordering::Relaxed) {
+                (StatusCode::Ok, "ready")
+            } else {
+                (StatusCode::ServiceUnavailable, "not ready")
+            }
+        }
+        _ => (StatusCode::NotFound, "not found"),
+    };
+
+    *res.status_mut() = status;
+    if let Err(err) = res.send(body.as_bytes()) {
+        warn!("health response fail: {}", err);
+    }
+}
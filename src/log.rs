@@ -6,11 +6,108 @@ use slog_stream;
 use slog_term;
 use slog_json;
 use slog_scope;
-use std::fs::OpenOptions;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::collections::HashMap;
 use std;
 
 use config;
 
+/// Log file rotated by size.
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    keep: u64,
+}
+
+impl RotatingFile {
+    /// Open log file in append mode.
+    fn open(path: &str, max_size: Option<u64>, keep: u64) -> io::Result<RotatingFile> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(path));
+        let size = try!(file.metadata()).len();
+        Ok(RotatingFile {
+            path: String::from(path),
+            file: file,
+            size: size,
+            max_size: max_size,
+            keep: keep,
+        })
+    }
+
+    /// Shift rotated files, move current file to `.1` and reopen it.
+    fn rotate(&mut self) -> io::Result<()> {
+        try!(self.file.flush());
+        if self.keep == 0 {
+            try!(fs::remove_file(&self.path));
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = format!("{}.{}", self.path, i);
+                if fs::metadata(&from).is_ok() {
+                    try!(fs::rename(&from, format!("{}.{}", self.path, i + 1)));
+                }
+            }
+            try!(fs::rename(&self.path, format!("{}.1", self.path)));
+        }
+
+        self.file = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                try!(self.rotate());
+            }
+        }
+
+        let size = try!(self.file.write(buf));
+        self.size += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Filter records by level, modules may override the level.
+struct ModuleFilter<D: Drain> {
+    drain: D,
+    level: Level,
+    /// Levels indexed by module name, the last part of the module path.
+    levels: HashMap<String, Level>,
+}
+
+impl<D: Drain> Drain for ModuleFilter<D> {
+    type Error = D::Error;
+
+    fn log(&self,
+           info: &Record,
+           values: &OwnedKeyValueList)
+           -> std::result::Result<(), Self::Error> {
+        let module = info.module().rsplit("::").next().unwrap_or("");
+        let level = self.levels.get(module).unwrap_or(&self.level);
+        if info.level().is_at_least(*level) {
+            self.drain.log(info, values)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Increase a log level by verbose, capped to trace.
+fn raise(level: Level, verbose: u64) -> Level {
+    let level = std::cmp::min(level.as_usize() as u64 + verbose, Level::Trace.as_usize() as u64);
+    Level::from_usize(level as usize).unwrap_or(Level::Trace)
+}
+
 /// Bare logger that only write to console.
 pub fn bootstrap() {
     slog_scope::set_global_logger(Logger::root(slog_term::streamer().full().build().ignore_err(),
@@ -20,11 +117,18 @@ pub fn bootstrap() {
 /// Full featured logger.
 /// Send log to console and log file, also handle log level.
 pub fn log(parameters: &config::Parameters, verbose: u64) {
-    // Stdout drain
-    let drain_term = slog_term::streamer().full().build().ignore_err();
+    // Stdout drain, JSON keep scope labels as structured fields
+    let drain_term: Box<Drain<Error = ()> + Send + Sync> = match parameters.log_format {
+        config::LogFormat::Term => Box::new(slog_term::streamer().full().build().ignore_err()),
+        config::LogFormat::Json => {
+            Box::new(slog_stream::stream(std::io::stdout(), slog_json::default()).ignore_err())
+        }
+    };
 
     // File drain
-    let log_file = OpenOptions::new().create(true).append(true).open(&parameters.log_file);
+    let log_file = RotatingFile::open(&parameters.log_file,
+                                      parameters.log_file_max_size,
+                                      parameters.log_file_keep);
     if log_file.is_err() {
         crit!("Fail to open log file at {:?}: {}",
               &parameters.log_file,
@@ -35,15 +139,22 @@ pub fn log(parameters: &config::Parameters, verbose: u64) {
         .ignore_err();
 
     // increase console log level if needed. Cap to trave
-    let console_level = parameters.log_level.as_usize() as u64 + verbose;
-    let console_level = std::cmp::min(console_level, Level::Trace.as_usize() as u64);
-    let console_level = Level::from_usize(console_level as usize).unwrap_or(Level::Trace);
+    let console_filter = ModuleFilter {
+        drain: drain_term,
+        level: raise(parameters.log_level, verbose),
+        levels: parameters.log_levels
+            .iter()
+            .map(|(module, level)| (module.clone(), raise(*level, verbose)))
+            .collect(),
+    };
+    let file_filter = ModuleFilter {
+        drain: file_drain,
+        level: parameters.log_level,
+        levels: parameters.log_levels.clone(),
+    };
 
     // Setup root logger
-    let root_log = Logger::root(Duplicate::new(LevelFilter::new(drain_term, console_level),
-                                               LevelFilter::new(file_drain, parameters.log_level))
-                                    .ignore_err(),
-                                o!());
+    let root_log = Logger::root(Duplicate::new(console_filter, file_filter).ignore_err(), o!());
 
     slog_scope::set_global_logger(root_log);
 }
@@ -3,9 +3,12 @@
 //! Beamium scrap Prometheus endpoint and forward metrics to Warp10.
 extern crate clap;
 extern crate yaml_rust;
+extern crate toml;
 extern crate time;
 extern crate hyper;
 extern crate hyper_native_tls;
+extern crate native_tls;
+extern crate openssl;
 extern crate cast;
 extern crate regex;
 #[macro_use(o, slog_log, slog_trace, slog_debug, slog_info, slog_warn, slog_error, slog_crit)]
@@ -16,24 +19,39 @@ extern crate slog_term;
 extern crate slog_stream;
 extern crate slog_json;
 extern crate nix;
+extern crate flate2;
+#[macro_use]
+extern crate lazy_static;
 
 use clap::App;
 use std::thread;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use nix::sys::signal;
 use std::time::Duration;
+use std::cmp;
+use std::panic;
 
 mod config;
 mod source;
 mod router;
 mod sink;
 mod log;
+mod stats;
+mod health;
+mod scheduler;
 
 include!("version.rs");
 
 static mut SIGINT: bool = false;
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Delay(ms) before restarting a panicked thread, doubled on each panic.
+const RESTART_BACKOFF: u64 = 1000;
+/// Maximum delay(ms) before restarting a panicked thread.
+const RESTART_BACKOFF_MAX: u64 = 60000;
 
 extern "C" fn handle_sigint(_: i32) {
     unsafe {
@@ -41,6 +59,409 @@ extern "C" fn handle_sigint(_: i32) {
     }
 }
 
+extern "C" fn handle_sighup(_: i32) {
+    RELOAD.store(true, Ordering::Relaxed);
+    router::RELOAD_LABELS.store(true, Ordering::Relaxed);
+}
+
+extern "C" fn handle_sigusr1(_: i32) {
+    sink::FLUSH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Running thread.
+/// Scheduled workers have no thread of their own, they hold a lock while running instead.
+struct Worker {
+    sigint: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+    running: Option<Arc<Mutex<()>>>,
+}
+
+impl Worker {
+    /// Ask the thread to stop and wait for it.
+    fn stop(self) {
+        self.sigint.store(true, Ordering::Relaxed);
+        self.join();
+    }
+
+    /// Wait for the thread, or for the current run of a scheduled worker.
+    fn join(self) {
+        if let Some(handle) = self.handle {
+            handle.join().unwrap();
+        }
+        if let Some(running) = self.running {
+            let _running = running.lock().unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Log a config summary, secrets are left out.
+fn summary(config: &config::Config) {
+    info!("{} sources", config.sources.len());
+    for source in &config.sources {
+        info!("source {}: {}", source.name, redact(&source.url));
+    }
+    info!("{} sinks", config.sinks.len());
+    for sink in &config.sinks {
+        info!("sink {}: {}, selector: {}",
+              sink.name,
+              sink.urls.iter().map(|url| redact(url)).collect::<Vec<String>>().join(", "),
+              sink.selector.is_some());
+    }
+    info!("labels: {}",
+          config.labels.keys().cloned().collect::<Vec<String>>().join(", "));
+    info!("scan-period: {}ms, batch-size: {}, batch-count: {}, source-dir: {}, sink-dir: {}",
+          config.parameters.scan_period,
+          config.parameters.batch_size,
+          config.parameters.batch_count,
+          config.parameters.source_dir,
+          config.parameters.sink_dir);
+}
+
+/// Redact url credentials.
+fn redact(url: &str) -> String {
+    match hyper::Url::parse(url) {
+        Ok(mut parsed) => {
+            if parsed.password().is_some() {
+                let _ = parsed.set_password(Some("<redacted>"));
+            }
+            // InfluxDB urls may carry credentials in their query
+            if parsed.query().is_some() {
+                let pairs: Vec<(String, String)> = parsed.query_pairs()
+                    .map(|(k, v)| {
+                        let secret = ["p", "password", "token"].contains(&k.as_ref());
+                        let v = if secret { String::from("redacted") } else { v.into_owned() };
+                        (k.into_owned(), v)
+                    })
+                    .collect();
+                parsed.query_pairs_mut().clear().extend_pairs(pairs);
+            }
+            parsed.into_string()
+        }
+        Err(_) => String::from(url),
+    }
+}
+
+/// Run a thread body, restart it when it panics or exit the process.
+fn supervise<F>(name: &str, parameters: &config::Parameters, sigint: &Arc<AtomicBool>, run: F)
+    where F: Fn()
+{
+    let mut backoff = RESTART_BACKOFF;
+    loop {
+        if panic::catch_unwind(panic::AssertUnwindSafe(|| run())).is_ok() {
+            return;
+        }
+
+        if !parameters.restart_on_panic {
+            crit!("{} panicked, exiting", name);
+            std::process::exit(-1);
+        }
+        crit!("{} panicked, restarting in {}ms", name, backoff);
+
+        // Backoff, but stay responsive to sigint
+        let mut slept = 0;
+        while slept < backoff {
+            if sigint.load(Ordering::Relaxed) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+            slept += 10;
+        }
+        backoff = cmp::min(backoff * 2, RESTART_BACKOFF_MAX);
+    }
+}
+
+/// Spawn a source thread.
+fn spawn_source(source: &config::Source, parameters: &config::Parameters) -> Worker {
+    let sigint = Arc::new(AtomicBool::new(false));
+    let (source, parameters, thread_sigint) = (source.clone(), parameters.clone(), sigint.clone());
+
+    // Run scrapes on the scheduler pool
+    if parameters.worker_threads.is_some() {
+        let logger = slog_scope::logger().new(o!("source" => source.name.clone()));
+        let delay = slog_scope::scope(logger.clone(), || source::prepare(&source, &parameters));
+        let name = format!("source:{}", source.name);
+        let restart_on_panic = parameters.restart_on_panic;
+        return schedule_worker(name, logger, restart_on_panic, delay, move |sigint| {
+            source::scrape(&source, &parameters, sigint)
+        });
+    }
+
+    let handle = thread::spawn(move || {
+        let name = format!("source:{}", source.name);
+        let _guard = health::register(&name);
+        slog_scope::scope(slog_scope::logger().new(o!("source" => source.name.clone())), || {
+            supervise(&name, &parameters, &thread_sigint, || {
+                source::source(&source, &parameters, thread_sigint.clone())
+            })
+        });
+    });
+
+    Worker {
+        sigint: sigint,
+        handle: Some(handle),
+        running: None,
+    }
+}
+
+/// Run a worker on the scheduler pool, run returns the delay before its next run.
+/// A panicking run is rescheduled like a thread restart, or exit the process.
+fn schedule_worker<F>(name: String,
+                      logger: slog::Logger,
+                      restart_on_panic: bool,
+                      delay: u64,
+                      mut run: F)
+                      -> Worker
+    where F: FnMut(&Arc<AtomicBool>) -> u64 + Send + 'static
+{
+    let sigint = Arc::new(AtomicBool::new(false));
+    let running = Arc::new(Mutex::new(()));
+    let guard = health::register(&name);
+    let (task_sigint, task_running) = (sigint.clone(), running.clone());
+    let mut backoff = RESTART_BACKOFF;
+
+    let task = move || {
+        // Stopping waits for the current run, and no run starts once stopped
+        let _running = task_running.lock().unwrap_or_else(|e| e.into_inner());
+        if task_sigint.load(Ordering::Relaxed) {
+            return 0;
+        }
+
+        slog_scope::scope(logger.clone(), || {
+            match panic::catch_unwind(panic::AssertUnwindSafe(|| run(&task_sigint))) {
+                Ok(delay) => {
+                    guard.alive(true);
+                    backoff = RESTART_BACKOFF;
+                    delay
+                }
+                Err(_) => {
+                    if !restart_on_panic {
+                        crit!("{} panicked, exiting", name);
+                        std::process::exit(-1);
+                    }
+                    crit!("{} panicked, restarting in {}ms", name, backoff);
+                    guard.alive(false);
+                    let delay = backoff;
+                    backoff = cmp::min(backoff * 2, RESTART_BACKOFF_MAX);
+                    delay
+                }
+            }
+        })
+    };
+    scheduler::schedule(delay, sigint.clone(), Box::new(task));
+
+    Worker {
+        sigint: sigint,
+        handle: None,
+        running: Some(running),
+    }
+}
+
+/// Spawn a scheduler thread.
+fn spawn_scheduler(index: u64) -> Worker {
+    let sigint = Arc::new(AtomicBool::new(false));
+    let thread_sigint = sigint.clone();
+    let handle = thread::spawn(move || {
+        let _guard = health::register(&format!("scheduler:{}", index));
+        slog_scope::scope(slog_scope::logger().new(o!()),
+                          || scheduler::worker(thread_sigint));
+    });
+
+    Worker {
+        sigint: sigint,
+        handle: Some(handle),
+        running: None,
+    }
+}
+
+/// Spawn the router thread.
+fn spawn_router(config: &config::Config) -> Worker {
+    let sigint = Arc::new(AtomicBool::new(false));
+    let (sinks, labels, dirs, parameters, thread_sigint) = (config.sinks.clone(),
+                                                            config.labels.clone(),
+                                                            config::source_dirs(config),
+                                                            config.parameters.clone(),
+                                                            sigint.clone());
+    let handle = thread::spawn(move || {
+        let _guard = health::register("router");
+        slog_scope::scope(slog_scope::logger().new(o!()), || {
+            supervise("router", &parameters, &thread_sigint, || {
+                router::router(&sinks, &labels, &dirs, &parameters, thread_sigint.clone())
+            })
+        });
+    });
+
+    Worker {
+        sigint: sigint,
+        handle: Some(handle),
+        running: None,
+    }
+}
+
+/// Spawn a sink thread.
+fn spawn_sink(sink: &config::Sink, parameters: &config::Parameters) -> Worker {
+    let sigint = Arc::new(AtomicBool::new(false));
+    let (sink, parameters, thread_sigint) = (sink.clone(), parameters.clone(), sigint.clone());
+
+    // Run pushes on the scheduler pool
+    if parameters.worker_threads.is_some() {
+        let logger = slog_scope::logger().new(o!("sink" => sink.name.clone()));
+        let mut runner = slog_scope::scope(logger.clone(), || sink::prepare(&sink, &parameters));
+        let name = format!("sink:{}", sink.name);
+        let restart_on_panic = parameters.restart_on_panic;
+        return schedule_worker(name, logger, restart_on_panic, 0, move |sigint| {
+            sink::run(&sink, &parameters, &mut runner, sigint)
+        });
+    }
+    let handle = thread::spawn(move || {
+        let name = format!("sink:{}", sink.name);
+        let _guard = health::register(&name);
+        slog_scope::scope(slog_scope::logger().new(o!("sink" => sink.name.clone())), || {
+            supervise(&name, &parameters, &thread_sigint, || {
+                sink::sink(&sink, &parameters, thread_sigint.clone())
+            })
+        });
+    });
+
+    Worker {
+        sigint: sigint,
+        handle: Some(handle),
+        running: None,
+    }
+}
+
+/// Scrape every source, then route, then push every sink, once.
+/// Sources and sinks each run concurrently, return true if every step succeeded.
+fn once(config: &config::Config) -> bool {
+    let sigint = Arc::new(AtomicBool::new(false));
+
+    let handles: Vec<thread::JoinHandle<bool>> = config.sources
+        .iter()
+        .map(|source| {
+            let (source, parameters, sigint) =
+                (source.clone(), config.parameters.clone(), sigint.clone());
+            thread::spawn(move || {
+                slog_scope::scope(slog_scope::logger().new(o!("source" => source.name.clone())),
+                                  || source::once(&source, &parameters, &sigint))
+            })
+        })
+        .collect();
+    let scraped = handles.into_iter()
+        .fold(true, |acc, handle| handle.join().unwrap_or(false) && acc);
+
+    let routed = router::once(&config.sinks,
+                              &config.labels,
+                              &config::source_dirs(config),
+                              &config.parameters,
+                              sigint.clone());
+
+    let handles: Vec<thread::JoinHandle<bool>> = config.sinks
+        .iter()
+        .map(|sink| {
+            let (sink, parameters, sigint) =
+                (sink.clone(), config.parameters.clone(), sigint.clone());
+            thread::spawn(move || {
+                slog_scope::scope(slog_scope::logger().new(o!("sink" => sink.name.clone())),
+                                  || sink::once(&sink, &parameters, sigint))
+            })
+        })
+        .collect();
+    let pushed = handles.into_iter()
+        .fold(true, |acc, handle| handle.join().unwrap_or(false) && acc);
+
+    scraped && routed && pushed
+}
+
+/// Spawn the stats thread.
+fn spawn_stats(parameters: &config::Parameters) -> Worker {
+    let sigint = Arc::new(AtomicBool::new(false));
+    let (parameters, thread_sigint) = (parameters.clone(), sigint.clone());
+    let handle = thread::spawn(move || {
+        let _guard = health::register("stats");
+        slog_scope::scope(slog_scope::logger().new(o!()), || {
+            supervise("stats", &parameters, &thread_sigint, || {
+                stats::stats(&parameters, thread_sigint.clone())
+            })
+        });
+    });
+
+    Worker {
+        sigint: sigint,
+        handle: Some(handle),
+        running: None,
+    }
+}
+
+/// Reload config and restart threads whose config changed.
+/// Keep the running config untouched if the new one is invalid.
+fn reload(config_path: &str,
+          config: &mut config::Config,
+          sources: &mut HashMap<String, Worker>,
+          router: &mut Option<Worker>,
+          sinks: &mut HashMap<String, Worker>) {
+    info!("reloading config");
+    let new_config = match config::load_config(config_path) {
+        Err(err) => {
+            crit!("Fail to reload config {}: {}", config_path, err);
+            return;
+        }
+        Ok(v) => v,
+    };
+
+    if new_config.parameters != config.parameters {
+        warn!("parameters changes require a restart");
+    }
+
+    // Sources
+    for source in &config.sources {
+        if !new_config.sources.contains(source) {
+            info!("stopping source {}", source.name);
+            if let Some(worker) = sources.remove(&source.name) {
+                worker.stop();
+            }
+        }
+    }
+    for source in &new_config.sources {
+        if !config.sources.contains(source) {
+            info!("spawning source {}", source.name);
+            sources.insert(source.name.clone(),
+                           spawn_source(source, &config.parameters));
+        }
+    }
+
+    // Router and sinks, the router also reads sources dirs
+    let dirs_changed = config::source_dirs(&new_config) != config::source_dirs(config);
+    config.sources = new_config.sources;
+    if new_config.sinks != config.sinks || new_config.labels != config.labels || dirs_changed {
+        info!("stopping router");
+        if let Some(worker) = router.take() {
+            worker.stop();
+        }
+
+        for sink in &config.sinks {
+            if !new_config.sinks.contains(sink) {
+                info!("stopping sink {}", sink.name);
+                if let Some(worker) = sinks.remove(&sink.name) {
+                    worker.stop();
+                }
+            }
+        }
+        for sink in &new_config.sinks {
+            if !config.sinks.contains(sink) {
+                info!("spawning sink {}", sink.name);
+                sinks.insert(sink.name.clone(), spawn_sink(sink, &config.parameters));
+            }
+        }
+
+        config.sinks = new_config.sinks;
+        config.labels = new_config.labels;
+
+        info!("spawning router");
+        *router = Some(spawn_router(config));
+    }
+
+    info!("config reloaded");
+}
+
 /// Main loop.
 fn main() {
     unsafe {
@@ -48,6 +469,17 @@ fn main() {
                                                 signal::SaFlags::empty(),
                                                 signal::SigSet::empty());
         signal::sigaction(signal::SIGINT, &sig_action).unwrap();
+        signal::sigaction(signal::SIGTERM, &sig_action).unwrap();
+
+        let sig_action = signal::SigAction::new(signal::SigHandler::Handler(handle_sighup),
+                                                signal::SaFlags::empty(),
+                                                signal::SigSet::empty());
+        signal::sigaction(signal::SIGHUP, &sig_action).unwrap();
+
+        let sig_action = signal::SigAction::new(signal::SigHandler::Handler(handle_sigusr1),
+                                                signal::SaFlags::empty(),
+                                                signal::SigSet::empty());
+        signal::sigaction(signal::SIGUSR1, &sig_action).unwrap();
     }
 
     // Setup a bare logger
@@ -59,7 +491,11 @@ fn main() {
         .about("Send Prometheus metrics to Warp10")
         .args_from_usage("-c, --config=[FILE] 'Sets a custom config file'
                               \
-                          -v...                'Increase verbosity level (console only)'")
+                          -v...                'Increase verbosity level (console only)'
+                              \
+                          --check-config       'Check config and exit'
+                              \
+                          --once               'Scrape, route and push once, then exit'")
         .get_matches();
 
     info!("starting");
@@ -73,84 +509,165 @@ fn main() {
               config.err().unwrap());
         std::process::exit(-1);
     }
-    let config = config.ok().unwrap();
+    let mut config = config.ok().unwrap();
+
+    // Check config only
+    if matches.is_present("check-config") {
+        if let Err(err) = config::check(&config) {
+            crit!("Invalid config {}: {}", &config_path, err);
+            std::process::exit(-1);
+        }
+        println!("{} sources, {} sinks, labels: {}",
+                 config.sources.len(),
+                 config.sinks.len(),
+                 config.labels.keys().cloned().collect::<Vec<String>>().join(", "));
+        std::process::exit(0);
+    }
 
     // Setup logging
     log::log(&config.parameters, matches.occurrences_of("v"));
+    summary(&config);
 
     // Ensure dirs
-    let dir = fs::create_dir_all(&config.parameters.source_dir);
+    let dir = sink::create_dir(&config.parameters.source_dir, &config.parameters);
     if dir.is_err() {
         crit!("Fail to create source directory {}: {}",
               &config.parameters.source_dir,
               dir.err().unwrap());
         std::process::exit(-1);
     }
-    let dir = fs::create_dir_all(&config.parameters.sink_dir);
+    let dir = sink::create_dir(&config.parameters.sink_dir, &config.parameters);
     if dir.is_err() {
         crit!("Fail to create sink directory {}: {}",
               &config.parameters.sink_dir,
               dir.err().unwrap());
         std::process::exit(-1);
     }
+    if let Some(ref dead_letter_dir) = config.parameters.dead_letter_dir {
+        let dir = sink::create_dir(dead_letter_dir, &config.parameters);
+        if dir.is_err() {
+            crit!("Fail to create dead letter directory {}: {}",
+                  dead_letter_dir,
+                  dir.err().unwrap());
+            std::process::exit(-1);
+        }
+    }
+    if let Some(ref corrupt_dir) = config.parameters.corrupt_dir {
+        let dir = sink::create_dir(corrupt_dir, &config.parameters);
+        if dir.is_err() {
+            crit!("Fail to create corrupt directory {}: {}",
+                  corrupt_dir,
+                  dir.err().unwrap());
+            std::process::exit(-1);
+        }
+    }
 
-    // Synchronisation stuff
-    // let signal = chan_signal::notify(&[Signal::INT, Signal::TERM]);
-    let sigint = Arc::new(AtomicBool::new(false));
-    let mut handles = Vec::with_capacity(config.sources.len() + 1 + config.sinks.len());
+    // One shot run
+    if matches.is_present("once") {
+        if once(&config) {
+            info!("done");
+            std::process::exit(0);
+        }
+        crit!("Fail to run once");
+        std::process::exit(-1);
+    }
+
+    // Spawn scheduler
+    let schedulers: Vec<Worker> = match config.parameters.worker_threads {
+        None => Vec::new(),
+        Some(threads) => {
+            info!("spawning {} scheduler threads", threads);
+            (0..threads).map(spawn_scheduler).collect()
+        }
+    };
 
     // Spawn sources
     info!("spawning sources");
-    for source in config.sources {
-        let (parameters, sigint) = (config.parameters.clone(), sigint.clone());
-        handles.push(thread::spawn(move || {
-            slog_scope::scope(slog_scope::logger().new(o!("source" => source.name.clone())),
-                              || source::source(&source, &parameters, sigint));
-        }));
+    let mut sources = HashMap::new();
+    for source in &config.sources {
+        sources.insert(source.name.clone(),
+                       spawn_source(source, &config.parameters));
     }
 
     // Spawn router
     info!("spawning router");
-    {
-        let (sinks, labels, parameters, sigint) = (config.sinks.clone(),
-                                                   config.labels.clone(),
-                                                   config.parameters.clone(),
-                                                   sigint.clone());
-        handles.push(thread::spawn(move || {
-            slog_scope::scope(slog_scope::logger().new(o!()),
-                              || router::router(&sinks, &labels, &parameters, sigint));
-        }));
-    }
+    let mut router = Some(spawn_router(&config));
 
     // Spawn sinks
     info!("spawning sinks");
-    for sink in config.sinks {
-        let (parameters, sigint) = (config.parameters.clone(), sigint.clone());
-        handles.push(thread::spawn(move || {
-            slog_scope::scope(slog_scope::logger().new(o!("sink" => sink.name.clone())),
-                              || sink::sink(&sink, &parameters, sigint));
-        }));
+    let mut sinks = HashMap::new();
+    for sink in &config.sinks {
+        sinks.insert(sink.name.clone(), spawn_sink(sink, &config.parameters));
     }
 
+    // Spawn stats
+    let stats = if config.parameters.self_metrics {
+        info!("spawning stats");
+        Some(spawn_stats(&config.parameters))
+    } else {
+        None
+    };
+
+    // Spawn health checks server
+    let mut listening = match config.parameters.listen {
+        None => None,
+        Some(ref addr) => {
+            info!("listening on {}", addr);
+            match health::listen(addr) {
+                Err(err) => {
+                    crit!("Fail to listen on {}: {}", addr, err);
+                    std::process::exit(-1);
+                }
+                Ok(v) => Some(v),
+            }
+        }
+    };
+
     info!("started");
     // Wait for sigint
     loop {
         thread::sleep(Duration::from_millis(10));
 
+        if RELOAD.swap(false, Ordering::Relaxed) {
+            reload(&config_path, &mut config, &mut sources, &mut router, &mut sinks);
+        }
+
         unsafe {
             if SIGINT {
-                sigint.store(true, Ordering::Relaxed);
+                break;
             }
         }
+    }
 
-        if sigint.load(Ordering::Relaxed) {
-            break;
+    info!("shutding down");
+    if let Some(ref mut listening) = listening {
+        if let Err(err) = listening.close() {
+            warn!("fail to stop health checks server: {}", err);
         }
     }
+    let workers: Vec<Worker> = sources.into_iter()
+        .map(|(_, w)| w)
+        .chain(router.into_iter())
+        .chain(sinks.into_iter().map(|(_, w)| w))
+        .chain(stats.into_iter())
+        .chain(schedulers.into_iter())
+        .collect();
+    for worker in &workers {
+        worker.sigint.store(true, Ordering::Relaxed);
+    }
 
-    info!("shutding down");
-    for handle in handles {
-        handle.join().unwrap();
+    // Wait for threads, but do not hang forever on a stuck one
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for worker in workers {
+            worker.join();
+        }
+        let _ = tx.send(());
+    });
+    let timeout = Duration::from_millis(config.parameters.shutdown_timeout);
+    if rx.recv_timeout(timeout).is_err() {
+        crit!("Fail to halt within {}ms", config.parameters.shutdown_timeout);
+        std::process::exit(-1);
     }
     info!("halted");
 }
@@ -2,198 +2,1518 @@
 //!
 //! The Router module forward sources to sinks.
 use std::thread;
+use std::panic;
 use std::time::Duration;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use time;
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
+use std::io::BufReader;
 use std::fs;
 use std::fs::File;
 use std::error::Error;
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::io;
+use std::fmt;
+use flate2;
+use flate2::write::GzEncoder;
+use nix::libc;
 
 use config;
+use stats;
+use health;
+use sink;
 
-/// Thread sleeping time.
-const REST_TIME: u64 = 10;
+/// Maximum number of distinct lines tracked for deduplication in a batch.
+/// Lines beyond it are passed through.
+const DEDUP_CAPACITY: usize = 1000000;
+
+/// Failed reads before a source file is moved to the corrupt dir.
+const CORRUPT_RETRY: u64 = 3;
+
+/// Set to read the labels file again without waiting for its period.
+pub static RELOAD_LABELS: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Routing failure, by kind.
+#[derive(Debug)]
+pub enum RouterError {
+    /// Source or sink dirs access.
+    Io(io::Error),
+    /// Source file content.
+    Parse(Box<Error>),
+    /// Sink tmp files write.
+    SinkWrite(io::Error),
+    /// No space left for sink files.
+    DiskFull(io::Error),
+}
+
+impl RouterError {
+    /// Kind name, used as metrics label.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            RouterError::Io(_) => "io",
+            RouterError::Parse(_) => "parse",
+            RouterError::SinkWrite(_) => "sink_write",
+            RouterError::DiskFull(_) => "disk_full",
+        }
+    }
+
+    /// Wrap a sink tmp file write error.
+    fn sink_write(err: io::Error) -> RouterError {
+        if err.raw_os_error() == Some(libc::ENOSPC) {
+            RouterError::DiskFull(err)
+        } else {
+            RouterError::SinkWrite(err)
+        }
+    }
+
+    /// Wrap a source file read error, content which is not UTF-8 is a parse error.
+    fn read(err: io::Error) -> RouterError {
+        if err.kind() == io::ErrorKind::InvalidData {
+            RouterError::Parse(Box::new(err))
+        } else {
+            RouterError::Io(err)
+        }
+    }
+}
+
+impl From<io::Error> for RouterError {
+    fn from(err: io::Error) -> RouterError {
+        if err.raw_os_error() == Some(libc::ENOSPC) {
+            RouterError::DiskFull(err)
+        } else {
+            RouterError::Io(err)
+        }
+    }
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RouterError::Io(ref err) => err.fmt(f),
+            RouterError::Parse(ref err) => err.fmt(f),
+            RouterError::SinkWrite(ref err) => write!(f, "sink write: {}", err),
+            RouterError::DiskFull(ref err) => write!(f, "disk full: {}", err),
+        }
+    }
+}
+
+impl Error for RouterError {
+    fn description(&self) -> &str {
+        match *self {
+            RouterError::Io(ref err) => err.description(),
+            RouterError::Parse(ref err) => err.description(),
+            RouterError::SinkWrite(ref err) => err.description(),
+            RouterError::DiskFull(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            RouterError::Io(ref err) => Some(err),
+            RouterError::Parse(ref err) => Some(err.as_ref()),
+            RouterError::SinkWrite(ref err) => Some(err),
+            RouterError::DiskFull(ref err) => Some(err),
+        }
+    }
+}
+
+/// Sink tmp file, optionally compressed.
+enum SinkFile {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+}
+
+impl SinkFile {
+    /// Flush data and terminate the gzip stream if any.
+    /// Syncing trades throughput for durability: without it a crash right after the rotation
+    /// can leave a renamed but empty file, `data` syncs the content, `full` also the metadata.
+    fn finish(self, fsync: &config::Fsync) -> io::Result<()> {
+        let mut file = match self {
+            SinkFile::Plain(file) => file,
+            SinkFile::Gzip(encoder) => try!(encoder.finish()),
+        };
+        try!(file.flush());
+
+        match *fsync {
+            config::Fsync::None => Ok(()),
+            config::Fsync::Data => file.sync_data(),
+            config::Fsync::Full => file.sync_all(),
+        }
+    }
+}
+
+impl Write for SinkFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            SinkFile::Plain(ref mut file) => file.write(buf),
+            SinkFile::Gzip(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            SinkFile::Plain(ref mut file) => file.flush(),
+            SinkFile::Gzip(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
 
 /// Router loop.
 pub fn router(sinks: &Vec<config::Sink>,
               labels: &HashMap<String, String>,
+              dirs: &Vec<String>,
               parameters: &config::Parameters,
               sigint: Arc<AtomicBool>) {
 
-    let labels: String = labels.iter()
-        .fold(String::new(), |acc, (k, v)| {
-            let sep = if acc.is_empty() { "" } else { "," };
-            acc + sep + k + "=" + v
-        });
+    let mut file_labels = HashMap::new();
+    let mut labels_read: Option<u64> = None;
+    let mut sinks_labels = sink_labels(sinks, labels);
+    let mut backpressure = false;
+    let mut failures: HashMap<PathBuf, u64> = HashMap::new();
+    let mut readers = Readers::start(parameters);
 
     loop {
         let start = time::now_utc();
 
-        match route(sinks, parameters, &labels) {
-            Err(err) => error!("route fail: {}", err),
-            Ok(_) => info!("route success"),
+        // Labels file changes apply to the next batches
+        if let Some(ref path) = parameters.labels_file {
+            let now = time::precise_time_ns() / 1000 / 1000;
+            let reload = RELOAD_LABELS.swap(false, Ordering::Relaxed);
+            let due = labels_read.map_or(true, |read| now - read >= parameters.labels_file_period);
+            if reload || due {
+                labels_read = Some(now);
+                if read_labels(path, &mut file_labels) {
+                    info!("labels file {} changed", path);
+                    let merged = merge_labels(labels, &file_labels);
+                    sinks_labels = sink_labels(sinks, &merged);
+                }
+            }
+        }
+
+        // Sinks list is fixed for the router lifetime, it is restarted on change
+        if parameters.cleanup_orphans {
+            if let Err(err) = sink::cleanup_orphans(sinks, parameters) {
+                error!("cleanup orphans fail: {}", err);
+            }
+        }
+
+        match route(sinks,
+                    parameters,
+                    dirs,
+                    &sinks_labels,
+                    &mut backpressure,
+                    &mut failures,
+                    &mut readers,
+                    sigint.clone()) {
+            Err(err) => {
+                stats::incr("router.errors", &format!("kind={}", err.kind()), 1);
+                match err {
+                    RouterError::DiskFull(_) => crit!("route fail: {}", err),
+                    _ => error!("route fail: {}", err),
+                }
+            }
+            Ok(_) => {
+                health::ready();
+                info!("route success")
+            }
         }
 
         let elapsed = (time::now_utc() - start).num_milliseconds() as u64;
         let sleep_time = if elapsed > parameters.scan_period {
-            REST_TIME
+            parameters.router_min_rest
         } else {
-            cmp::max(parameters.scan_period - elapsed, REST_TIME)
+            cmp::max(parameters.scan_period - elapsed, parameters.router_min_rest)
         };
-        for _ in 0..sleep_time / REST_TIME {
-            thread::sleep(Duration::from_millis(REST_TIME));
+        for _ in 0..cmp::max(sleep_time / parameters.router_rest_time, 1) {
+            thread::sleep(Duration::from_millis(parameters.router_rest_time));
             if sigint.load(Ordering::Relaxed) {
+                cleanup(sinks, parameters);
                 return;
             }
         }
     }
 }
 
+/// Route sources once, until every dir is empty.
+pub fn once(sinks: &Vec<config::Sink>,
+            labels: &HashMap<String, String>,
+            dirs: &Vec<String>,
+            parameters: &config::Parameters,
+            sigint: Arc<AtomicBool>)
+            -> bool {
+    let mut file_labels = HashMap::new();
+    if let Some(ref path) = parameters.labels_file {
+        read_labels(path, &mut file_labels);
+    }
+    let labels = sink_labels(sinks, &merge_labels(labels, &file_labels));
+    let mut backpressure = false;
+    let mut failures: HashMap<PathBuf, u64> = HashMap::new();
+
+    match route(sinks,
+                parameters,
+                dirs,
+                &labels,
+                &mut backpressure,
+                &mut failures,
+                &mut Readers::start(parameters),
+                sigint) {
+        Err(err) => {
+            error!("route fail: {}", err);
+            false
+        }
+        Ok(_) => {
+            info!("route success");
+            true
+        }
+    }
+}
+
+/// Read the labels file, keep the previous labels if it is unreadable.
+/// Return true if labels changed.
+fn read_labels(path: &str, labels: &mut HashMap<String, String>) -> bool {
+    match config::load_labels(path) {
+        Err(err) => {
+            warn!("fail to read labels file {}: {}", path, err);
+            false
+        }
+        Ok(v) => {
+            if v == *labels {
+                return false;
+            }
+            *labels = v;
+            true
+        }
+    }
+}
+
+/// Merge config labels with the labels file ones, which override them.
+fn merge_labels(labels: &HashMap<String, String>,
+                file_labels: &HashMap<String, String>)
+                -> HashMap<String, String> {
+    let mut merged = labels.clone();
+    merged.extend(file_labels.clone());
+    merged
+}
+
+/// Build labels for each sink, sink labels override global ones.
+fn sink_labels(sinks: &Vec<config::Sink>,
+               labels: &HashMap<String, String>)
+               -> Vec<Vec<(String, String)>> {
+    sinks.iter()
+        .map(|sink| {
+            let mut sink_labels = labels.clone();
+            sink_labels.extend(sink.labels.clone());
+            sink_labels.into_iter().collect()
+        })
+        .collect()
+}
+
+/// Format labels for a source file.
+/// Source name references are expanded before labels are encoded.
+fn format_labels(labels: &Vec<(String, String)>,
+                 source: &str,
+                 parameters: &config::Parameters)
+                 -> String {
+    labels.iter().fold(String::new(), |acc, &(ref k, ref v)| {
+        let sep = if acc.is_empty() { "" } else { "," };
+        let v = v.replace(config::SOURCE_NAME, source);
+        if parameters.encode_labels {
+            acc + sep + &encode(k) + "=" + &encode(&v)
+        } else {
+            acc + sep + k + "=" + &v
+        }
+    })
+}
+
 /// Route handle sources forwarding.
+/// Source dirs are routed in turn, one batch each, so a busy dir does not starve the others.
 fn route(sinks: &Vec<config::Sink>,
          parameters: &config::Parameters,
-         labels: &String)
-         -> Result<(), Box<Error>> {
+         dirs: &Vec<String>,
+         labels: &Vec<Vec<(String, String)>>,
+         backpressure: &mut bool,
+         failures: &mut HashMap<PathBuf, u64>,
+         readers: &mut Readers,
+         sigint: Arc<AtomicBool>)
+         -> Result<(), RouterError> {
     debug!("route");
     loop {
-        let entries = try!(fs::read_dir(&parameters.source_dir));
-        let mut files = Vec::with_capacity(parameters.batch_count as usize);
-        let mut metrics: Vec<String> = Vec::new();
-
-        // Load metrics
-        let mut batch_size = 0;
-        for (i, entry) in entries.enumerate() {
-            let entry = try!(entry);
-            // Look only for metrics files
-            if entry.path().extension() != Some(OsStr::new("metrics")) {
+        // Stop consuming sources while sinks are full, source files are left in place
+        if let Some(max_size) = parameters.sink_dir_max_size {
+            let size = try!(dir_size(&parameters.sink_dir));
+            if size > max_size {
+                if !*backpressure {
+                    warn!("sink dir is full ({} > {} bytes), pause routing", size, max_size);
+                    *backpressure = true;
+                }
+                break;
+            }
+            if *backpressure {
+                info!("sink dir is no longer full, resume routing");
+                *backpressure = false;
+            }
+        }
+
+        let mut routed = false;
+        for dir in dirs {
+            let entries = try!(files(dir, &parameters.source_extension));
+            if entries.is_empty() {
                 continue;
             }
 
-            // Split metrics in capped batch
-            if i > parameters.batch_count as usize || batch_size > parameters.batch_size as usize {
-                break;
+            // Setup sinks files
+            // Never leave half written tmp files, source files are kept for the next route
+            let mut files = Vec::with_capacity(parameters.batch_count as usize);
+            if let Err(err) = load(sinks,
+                                   parameters,
+                                   labels,
+                                   &entries,
+                                   &mut files,
+                                   failures,
+                                   readers) {
+                cleanup(sinks, parameters);
+                return Err(err);
             }
 
-            debug!("open source file {}", format!("{:?}", entry.path()));
-            let file = match read(entry.path()) {
-                Err(err) => {
-                    warn!(err);
+            // Every file was unreadable
+            if files.len() == 0 {
+                cleanup(sinks, parameters);
+                continue;
+            }
+            let sink_dir = Path::new(&parameters.sink_dir);
+
+            // Rotate
+            let now = time::now_utc().to_timespec();
+            let now = format!("{}{:09}", now.sec, now.nsec);
+            for sink in sinks {
+                let ext = match parameters.compression {
+                    config::Compression::None => "metrics",
+                    config::Compression::Gzip => "metrics.gz",
+                };
+                let dest_file = rotation_path(sink_dir, &sink.name, &now, ext);
+                debug!("rotate tmp sink file to {}", format!("{:?}", dest_file));
+                try!(fs::rename(sink_dir.join(format!("{}.tmp", sink.name)), dest_file));
+                stats::incr("router.rotations", "", 1);
+            }
+
+            // Persist renames before deleting source files
+            if parameters.fsync == config::Fsync::Full {
+                try!(try!(File::open(sink_dir)).sync_all());
+            }
+
+            // Delete forwarded data
+            for f in files {
+                debug!("delete source file {}", format!("{:?}", f));
+                try!(fs::remove_file(f));
+                stats::incr("router.files.deleted", "", 1);
+            }
+            routed = true;
+        }
+
+        // Nothing to do
+        if !routed {
+            break;
+        }
+
+        // Stop after the current batch on shutdown
+        if sigint.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Route a capped batch of source files to sinks tmp files.
+/// Files are pushed to `files` once all their metrics are written.
+fn load(sinks: &Vec<config::Sink>,
+        parameters: &config::Parameters,
+        labels: &Vec<Vec<(String, String)>>,
+        entries: &Vec<fs::DirEntry>,
+        files: &mut Vec<PathBuf>,
+        failures: &mut HashMap<PathBuf, u64>,
+        readers: &mut Readers)
+        -> Result<(), RouterError> {
+    let mut writer = try!(SinkWriter::open(sinks, parameters));
+    let mut seen: HashSet<String> = HashSet::new();
+
+    // Load metrics, files are checked ahead by the readers but written in order
+    readers.clear();
+    let ahead = readers.count;
+    for (i, entry) in entries.iter().enumerate().take(ahead) {
+        readers.submit(i, entry.path());
+    }
+
+    let mut batch_size = 0;
+    let mut batch_lines = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        // Split metrics in capped batch
+        if i > parameters.batch_count as usize || batch_size > parameters.batch_size as usize {
+            break;
+        }
+        // The first file is always taken so an oversized one cannot block the batch
+        let full = parameters.batch_lines.map_or(false, |max| batch_lines >= max);
+        if full && !files.is_empty() {
+            break;
+        }
+
+        let checked = readers.take(i);
+        if let Some(next) = entries.get(i + ahead) {
+            readers.submit(i + ahead, next.path());
+        }
+
+        // Nothing from an unreadable file, or a strictly invalid one, is forwarded
+        let size = match checked {
+            Err(err) => {
+                quarantine(entry.path(), &err, parameters, failures);
+                continue;
+            }
+            Ok(v) => {
+                failures.remove(&entry.path());
+                v
+            }
+        };
+
+        // Expand source name references in labels
+        let source = source_name(&entry.path());
+        let file_labels: Vec<String> = labels.iter()
+            .map(|labels| format_labels(labels, &source, parameters))
+            .collect();
+
+        // Lines are streamed, so memory does not grow with the file size
+        debug!("route source file {}", format!("{:?}", entry.path()));
+        let reader = BufReader::new(try!(File::open(entry.path())));
+        for line in reader.lines() {
+            let line = try!(line.map_err(RouterError::read));
+            let line = line.as_str();
+            if parameters.validation == config::Validation::Lenient {
+                if let Err(err) = validate(line) {
+                    debug!("{}: {}", err, line);
+                    stats::incr("router.dropped", "reason=invalid", 1);
                     continue;
                 }
-                Ok(v) => v,
+            }
+            if parameters.drop_nan && is_nan(line) {
+                stats::incr("router.dropped", "reason=nan", 1);
+                continue;
+            }
+            let line = match parameters.metric_mapper {
+                config::MetricMapper::None => String::from(line),
+                config::MetricMapper::Dots => dots(line),
+            };
+            let line = if parameters.encode_labels {
+                match encode_labels(&line) {
+                    Err(err) => {
+                        warn!("{}: {}", err, line);
+                        stats::incr("router.dropped", "reason=labels", 1);
+                        continue;
+                    }
+                    Ok(v) => v,
+                }
+            } else {
+                line
             };
+            if parameters.dedup {
+                if seen.contains(&line) {
+                    stats::incr("router.dropped", "reason=dedup", 1);
+                    continue;
+                }
+                if seen.len() < DEDUP_CAPACITY {
+                    seen.insert(line.clone());
+                }
+            }
+            try!(writer.write(sinks, parameters, &file_labels, &line));
+            batch_lines += 1;
+        }
+
+        stats::incr("router.files.read", "", 1);
+        files.push(entry.path());
+        batch_size += size;
+    }
+
+    writer.finish(sinks, parameters)
+}
+
+/// Sinks tmp files of a batch.
+struct SinkWriter {
+    files: Vec<SinkFile>,
+    counts: Vec<u64>,
+}
+
+impl SinkWriter {
+    /// Open sinks tmp files.
+    fn open(sinks: &Vec<config::Sink>,
+            parameters: &config::Parameters)
+            -> Result<SinkWriter, RouterError> {
+        let dir = Path::new(&parameters.sink_dir);
+        let mut files = Vec::with_capacity(sinks.len() as usize);
+        for sink in sinks {
+            let sink_file = dir.join(format!("{}.tmp", sink.name));
+            debug!("open tmp sink file {}", format!("{:?}", sink_file));
+            let file = try!(sink::create_file(&sink_file, parameters)
+                .map_err(RouterError::sink_write));
+            files.push(match parameters.compression {
+                config::Compression::None => SinkFile::Plain(file),
+                config::Compression::Gzip => {
+                    SinkFile::Gzip(GzEncoder::new(file, flate2::Compression::Default))
+                }
+            });
+        }
+
+        Ok(SinkWriter {
+            files: files,
+            counts: vec![0; sinks.len()],
+        })
+    }
 
-            for line in file.lines() {
-                if labels.is_empty() {
-                    metrics.push(String::from(line));
+    /// Write a metric to every sink selecting it.
+    fn write(&mut self,
+             sinks: &Vec<config::Sink>,
+             parameters: &config::Parameters,
+             labels: &Vec<String>,
+             line: &str)
+             -> Result<(), RouterError> {
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        // With tenants, a metric only goes to its tenant sink
+        let target = parameters.tenant_label.as_ref().map(|label| tenant(line, label, parameters));
+        if target == Some(None) {
+            stats::incr("router.dropped", "reason=tenant", 1);
+            return Ok(());
+        }
+
+        for (i, sink) in sinks.iter().enumerate() {
+            if let Some(Some(target)) = target {
+                if target != sink.name {
                     continue;
                 }
-                let mut parts = line.splitn(2, "{");
+            }
 
-                let class = match parts.next() {
-                    None => {
-                        warn!("no_class");
+            let line = match add_labels(line, &labels[i]) {
+                Err(err) => {
+                    warn!("{}", err);
+                    continue;
+                }
+                Ok(v) => v,
+            };
+            let line = if parameters.sort_labels {
+                match sort_labels(&line) {
+                    Err(err) => {
+                        warn!("{}", err);
                         continue;
                     }
-                    Some(v) => v,
+                    Ok(v) => v,
+                }
+            } else {
+                line
+            };
+
+            if sink.selector.is_some() {
+                let selector = sink.selector.as_ref().unwrap();
+                let matched = parse_metric_line(&line)
+                    .map(|metric| selector.is_match(metric.class))
+                    .unwrap_or(false);
+                // Drop mode skips matching metrics, Match mode skips the others
+                let skip = match sink.selector_mode {
+                    config::SelectorMode::Drop => matched,
+                    config::SelectorMode::Match => !matched,
                 };
-                let class = String::from(class);
-                let plabels = match parts.next() {
+                if skip {
+                    continue;
+                }
+            }
+            // Both selectors have to keep the metric
+            if let Some(ref selector) = sink.label_selector {
+                let matched = parse_metric_line(&line)
+                    .ok()
+                    .and_then(|metric| parse_labels(metric.labels.unwrap_or("")).ok())
+                    .map_or(false, |labels| {
+                        labels.iter().any(|&(ref k, ref v)| {
+                            *k == selector.label && selector.regex.is_match(v)
+                        })
+                    });
+                if !matched {
+                    continue;
+                }
+            }
+            // Selectors match the original class, comments are kept as is
+            let prefixed = sink.class_prefix.as_ref().and_then(|prefix| {
+                parse_metric_line(&line).ok().map(|metric| {
+                    metric.format(&format!("{}{}", prefix, metric.class),
+                                  metric.labels.unwrap_or(""))
+                })
+            });
+            let line = prefixed.unwrap_or(line);
+            try!(self.files[i].write_all(line.as_bytes()).map_err(RouterError::sink_write));
+            try!(self.files[i].write_all(b"\n").map_err(RouterError::sink_write));
+            self.counts[i] += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Flush sinks tmp files.
+    fn finish(self,
+              sinks: &Vec<config::Sink>,
+              parameters: &config::Parameters)
+              -> Result<(), RouterError> {
+        for (i, sink) in sinks.iter().enumerate() {
+            stats::incr("router.metrics", &format!("sink={}", sink.name), self.counts[i]);
+        }
+
+        for file in self.files {
+            try!(file.finish(&parameters.fsync).map_err(RouterError::sink_write));
+        }
+
+        Ok(())
+    }
+}
+
+/// Get a metric line tenant sink, metrics without a known tenant go to the default sink.
+fn tenant<'a>(line: &str, label: &str, parameters: &'a config::Parameters) -> Option<&'a str> {
+    let value = parse_metric_line(line)
+        .ok()
+        .and_then(|metric| parse_labels(metric.labels.unwrap_or("")).ok())
+        .and_then(|labels| labels.into_iter().find(|&(ref k, _)| k == label).map(|(_, v)| v));
+
+    value.and_then(|v| parameters.tenants.get(&v))
+        .or(parameters.tenant_default.as_ref())
+        .map(|sink| sink.as_str())
+}
+
+/// Check if a metric line value is NaN or infinite.
+/// Comments are never considered as NaN.
+fn is_nan(line: &str) -> bool {
+    if line.trim_left().starts_with("#") {
+        return false;
+    }
+
+    match line.split_whitespace().last() {
+        Some("NaN") | Some("+Inf") | Some("-Inf") => true,
+        _ => false,
+    }
+}
+
+/// Sensision metric line, fields borrow the line.
+pub struct Metric<'a> {
+    /// Time and location, `TS/LAT:LON/ELEV`, each part may be empty.
+    pub prefix: &'a str,
+    pub class: &'a str,
+    /// Labels block content, None when the line has no labels block.
+    pub labels: Option<&'a str>,
+    pub value: &'a str,
+}
+
+impl<'a> Metric<'a> {
+    /// Format the metric back with another class and labels.
+    pub fn format(&self, class: &str, labels: &str) -> String {
+        format!("{} {}{{{}}} {}", self.prefix, class, labels, self.value)
+    }
+}
+
+/// Parse a Sensision metric line.
+/// Its labels block may contain spaces and braces inside quoted values.
+pub fn parse_metric_line(line: &str) -> Result<Metric, Box<Error>> {
+    let line = line.trim();
+    if line.starts_with("#") {
+        return Err(From::from("comment"));
+    }
+
+    let start = try!(line.find(' ').ok_or("no_class"));
+    let prefix = &line[..start];
+    let rest = line[start..].trim_left();
+
+    let (class, labels, rest) = match rest.find(|c: char| c == '{' || c == ' ') {
+        None => return Err(From::from("no_value")),
+        Some(i) if rest[i..].starts_with("{") => {
+            let (_, end) = try!(labels_block(&rest[i..]).ok_or("unterminated labels"));
+            (&rest[..i], Some(&rest[i + 1..i + end]), &rest[i + end + 1..])
+        }
+        Some(i) => (&rest[..i], None, &rest[i..]),
+    };
+    if class.is_empty() {
+        return Err(From::from("no_class"));
+    }
+    let value = rest.trim();
+    if value.is_empty() {
+        return Err(From::from("no_value"));
+    }
+
+    Ok(Metric {
+        prefix: prefix,
+        class: class,
+        labels: labels,
+        value: value,
+    })
+}
+
+/// Check a metric line has a class, balanced braces and a Warp10 value.
+/// Comments and empty lines are valid.
+pub fn validate(line: &str) -> Result<(), Box<Error>> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with("#") {
+        return Ok(());
+    }
+
+    let metric = try!(parse_metric_line(line));
+    if metric.class.contains('}') || metric.value.contains('{') || metric.value.contains('}') {
+        return Err(From::from("unbalanced braces"));
+    }
+
+    let value = metric.value;
+    let valid = match value {
+        "T" | "F" | "true" | "false" | "NaN" | "+Inf" | "-Inf" => true,
+        _ => {
+            value.parse::<f64>().is_ok() ||
+            (value.len() > 1 && value.starts_with("'") && value.ends_with("'"))
+        }
+    };
+    if !valid {
+        return Err(From::from("bad value"));
+    }
+
+    Ok(())
+}
+
+/// Replace underscores by dots in a metric line class, labels are left untouched.
+fn dots(line: &str) -> String {
+    match parse_metric_line(line) {
+        Err(_) => String::from(line),
+        Ok(metric) => metric.format(&metric.class.replace("_", "."), metric.labels.unwrap_or("")),
+    }
+}
+
+/// Find a metric line labels block, braces inside quoted values are skipped.
+fn labels_block(line: &str) -> Option<(usize, usize)> {
+    let start = match line.find('{') {
+        None => return None,
+        Some(i) => i,
+    };
+
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line[start..].char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '}' if !quoted => return Some((start, start + i)),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Parse a labels block content.
+/// Values may be quoted, quotes and backslashes are then escaped by a backslash.
+pub fn parse_labels(labels: &str) -> Result<Vec<(String, String)>, Box<Error>> {
+    let mut parsed = Vec::new();
+    let mut chars = labels.chars().peekable();
+
+    loop {
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if chars.next().is_none() {
+            // Trailing separator
+            if key.trim().is_empty() {
+                break;
+            }
+            return Err(From::from("bad label"));
+        }
+
+        let mut value = String::new();
+        let mut last = false;
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => return Err(From::from("unterminated label value")),
+                    Some('"') => break,
+                    Some('\\') => {
+                        match chars.next() {
+                            None => return Err(From::from("unterminated label value")),
+                            Some('n') => value.push('\n'),
+                            Some(c) => value.push(c),
+                        }
+                    }
+                    Some(c) => value.push(c),
+                }
+            }
+            match chars.next() {
+                None => last = true,
+                Some(',') => {}
+                Some(_) => return Err(From::from("bad label")),
+            }
+        } else {
+            loop {
+                match chars.next() {
                     None => {
-                        warn!("no_labels");
-                        continue;
+                        last = true;
+                        break;
                     }
-                    Some(v) => v,
-                };
-                let plabels = String::from(plabels);
+                    Some(',') => break,
+                    Some(c) => value.push(c),
+                }
+            }
+        }
+
+        parsed.push((String::from(key.trim()), value));
+        if last {
+            break;
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// URL encode Warp10 labels reserved characters.
+/// Percent signs are kept so already encoded labels are left untouched.
+fn encode(s: &str) -> String {
+    s.chars().fold(String::new(), |mut acc, c| {
+        match c {
+            '{' | '}' | ',' | '=' | '"' | ' ' | '\n' | '\r' | '\t' => {
+                acc.push_str(&format!("%{:02X}", c as u32))
+            }
+            _ => acc.push(c),
+        }
+        acc
+    })
+}
 
-                let slabels = labels.clone() +
-                              if plabels.trim().starts_with("}") {
-                    ""
-                } else {
-                    ","
-                } + &plabels;
+/// URL decode Warp10 class and labels, invalid escapes are kept as is.
+pub fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let high = (bytes[i + 1] as char).to_digit(16);
+            let low = (bytes[i + 2] as char).to_digit(16);
+            if let (Some(high), Some(low)) = (high, low) {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
 
-                metrics.push(format!("{}{{{}", class, slabels))
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Rewrite a metric line labels as Warp10 encoded ones.
+/// Comments are left untouched.
+fn encode_labels(line: &str) -> Result<String, Box<Error>> {
+    if line.trim_left().starts_with("#") {
+        return Ok(String::from(line));
+    }
+
+    let metric = try!(parse_metric_line(line));
+    let labels = try!(parse_labels(metric.labels.unwrap_or("")))
+        .iter()
+        .map(|&(ref k, ref v)| format!("{}={}", encode(k), encode(v)))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    Ok(metric.format(metric.class, &labels))
+}
+
+/// Split a labels block content on commas, commas inside quoted values are skipped.
+fn split_labels(labels: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in labels.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&labels[start..i]);
+                start = i + 1;
             }
+            _ => {}
+        }
+    }
+    parts.push(&labels[start..]);
+
+    parts.into_iter().filter(|label| !label.trim().is_empty()).collect()
+}
+
+/// Get a label key.
+fn label_key(label: &str) -> &str {
+    label.split('=').next().unwrap_or("").trim()
+}
+
+/// Sort a metric line labels by key, values are kept as is.
+/// Comments are left untouched.
+fn sort_labels(line: &str) -> Result<String, Box<Error>> {
+    if line.trim_left().starts_with("#") {
+        return Ok(String::from(line));
+    }
+
+    let metric = try!(parse_metric_line(line));
+    let mut labels = split_labels(metric.labels.unwrap_or(""));
+    labels.sort_by(|a, b| label_key(a).cmp(label_key(b)));
+
+    Ok(metric.format(metric.class, &labels.join(",")))
+}
+
+/// Inject labels into a metric line.
+/// Comments are left untouched, unlabeled metrics get a labels block.
+fn add_labels(line: &str, labels: &str) -> Result<String, Box<Error>> {
+    if labels.is_empty() || line.trim_left().starts_with("#") {
+        return Ok(String::from(line));
+    }
+
+    let metric = try!(parse_metric_line(line));
+    let plabels = metric.labels.unwrap_or("");
+
+    // Empty labels block does not need a separator
+    if plabels.trim().is_empty() {
+        Ok(metric.format(metric.class, labels))
+    } else {
+        Ok(metric.format(metric.class, &format!("{},{}", labels, plabels)))
+    }
+}
 
-            files.push(entry.path());
-            batch_size += file.len();
+/// Get the name of the source which wrote a file, files are named `<source>-<timestamp>.<ext>`.
+fn source_name(path: &Path) -> String {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    match file_name.rfind('-') {
+        Some(i) => String::from(&file_name[..i]),
+        None => String::from(file_name.split('.').next().unwrap_or("")),
+    }
+}
+
+/// Track unreadable source files, they are moved to the corrupt dir once they failed too often.
+fn quarantine(path: PathBuf,
+              err: &str,
+              parameters: &config::Parameters,
+              failures: &mut HashMap<PathBuf, u64>) {
+    let corrupt_dir = match parameters.corrupt_dir {
+        None => {
+            warn!("{}", err);
+            return;
         }
+        Some(ref dir) => dir,
+    };
 
-        // Nothing to do
-        if files.len() == 0 {
-            break;
+    let count = {
+        let count = failures.entry(path.clone()).or_insert(0);
+        *count += 1;
+        *count
+    };
+    if count < CORRUPT_RETRY {
+        debug!("{} ({}/{})", err, count, CORRUPT_RETRY);
+        return;
+    }
+    failures.remove(&path);
+
+    let dest_file = Path::new(corrupt_dir).join(path.file_name().unwrap_or_default());
+    match sink::move_file(&path, &dest_file) {
+        Err(move_err) => error!("move {:?} to corrupt dir fail: {}", path, move_err),
+        Ok(_) => {
+            warn!("{}, moved to {:?}", err, dest_file);
+            stats::incr("router.files.corrupt", "", 1);
         }
+    }
+}
 
-        // Setup sinks files
-        let dir = Path::new(&parameters.sink_dir);
-        {
-            let mut sink_files = Vec::with_capacity(sinks.len() as usize);
-            // Open tmp files
-            for sink in sinks {
-                let sink_file = dir.join(format!("{}.tmp", sink.name));
-                debug!("open tmp sink file {}", format!("{:?}", sink_file));
-                sink_files.push(try!(File::create(sink_file)));
+/// Remove sinks tmp files left by an interrupted route.
+fn cleanup(sinks: &Vec<config::Sink>, parameters: &config::Parameters) {
+    let dir = Path::new(&parameters.sink_dir);
+    for sink in sinks {
+        let sink_file = dir.join(format!("{}.tmp", sink.name));
+        if sink_file.exists() {
+            debug!("remove tmp sink file {}", format!("{:?}", sink_file));
+            if let Err(err) = fs::remove_file(&sink_file) {
+                warn!("fail to remove tmp sink file {:?}: {}", sink_file, err);
             }
+        }
+    }
+}
 
-            // Write metrics
-            debug!("write sink files");
-            for line in metrics {
-                if line.is_empty() {
-                    continue;
+/// List source metrics files, oldest first.
+fn files(dir: &str, extension: &str) -> io::Result<Vec<fs::DirEntry>> {
+    let mut entries = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        // Look only for metrics files, tmp files are still being written by sources
+        if entry.path().extension() == Some(OsStr::new("tmp")) {
+            continue;
+        }
+        if entry.path().extension() != Some(OsStr::new(extension)) {
+            continue;
+        }
+
+        let modified = match entry.metadata().and_then(|meta| meta.modified()) {
+            Err(err) => {
+                warn!("skip source file {:?}: {}", entry.path(), err);
+                continue;
+            }
+            Ok(v) => v,
+        };
+        entries.push((modified, entry));
+    }
+
+    // Identical mtimes fall back to file name order
+    entries.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.file_name().cmp(&b.1.file_name())));
+
+    Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// Compute the size of metrics files in a directory.
+fn dir_size(dir: &str) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let file_name = String::from(entry.file_name().to_str().unwrap_or(""));
+        if !file_name.ends_with(".metrics") && !file_name.ends_with(".metrics.gz") {
+            continue;
+        }
+        size += try!(entry.metadata()).len();
+    }
+
+    Ok(size)
+}
+
+/// Build a sink file path that does not exist yet, so a rotation never overwrite
+/// a file not forwarded yet, even if the clock goes backwards.
+/// Collisions are numbered after a dot, sink names may contain dashes.
+fn rotation_path(dir: &Path, sink: &str, now: &str, ext: &str) -> PathBuf {
+    let mut path = dir.join(format!("{}-{}.{}", sink, now, ext));
+    let mut i = 0;
+    while path.exists() {
+        i += 1;
+        path = dir.join(format!("{}-{}.{}.{}", sink, now, i, ext));
+    }
+
+    path
+}
+
+/// Pool of source file readers, files are checked ahead concurrently and taken in order.
+/// Files are fully checked, so one which is not text fails before any of its lines is routed.
+/// Only their size is kept, lines are read again while routing.
+struct Readers {
+    /// Number of readers, also the number of files checked ahead.
+    count: usize,
+    jobs: mpsc::Sender<(usize, PathBuf)>,
+    results: mpsc::Receiver<(usize, Result<usize, String>)>,
+    /// Files checked before the previous ones were taken.
+    ready: HashMap<usize, Result<usize, String>>,
+    /// Files queued and not read yet.
+    pending: usize,
+}
+
+impl Readers {
+    /// Start parameters.router-read-parallelism readers, they stop once the pool is dropped.
+    fn start(parameters: &config::Parameters) -> Readers {
+        let count = cmp::max(parameters.router_read_parallelism as usize, 1);
+        let strict = parameters.validation == config::Validation::Strict;
+        let (jobs, queue) = mpsc::channel::<(usize, PathBuf)>();
+        let (done, results) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..count {
+            let (queue, done) = (queue.clone(), done.clone());
+            thread::spawn(move || loop {
+                let job = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let (i, path) = match job {
+                    Err(_) => break,
+                    Ok(v) => v,
+                };
+                let result = panic::catch_unwind(|| check(path, strict))
+                    .unwrap_or(Err(RouterError::Parse(From::from("reader panicked"))))
+                    .map_err(|err| format!("{}", err));
+                if done.send((i, result)).is_err() {
+                    break;
                 }
+            });
+        }
 
-                for (i, sink) in sinks.iter().enumerate() {
-                    if sink.selector.is_some() {
-                        let selector = sink.selector.as_ref().unwrap();
-                        if line.split_whitespace()
-                            .nth(1)
-                            .map_or(false, |class| selector.is_match(class)) {
-                            continue;
-                        }
-                    }
-                    try!(sink_files[i].write(line.as_bytes()));
-                    try!(sink_files[i].write(b"\n"));
+        Readers {
+            count: count,
+            jobs: jobs,
+            results: results,
+            ready: HashMap::new(),
+            pending: 0,
+        }
+    }
+
+    /// Queue a file to read.
+    fn submit(&mut self, i: usize, path: PathBuf) {
+        if self.jobs.send((i, path)).is_ok() {
+            self.pending += 1;
+        }
+    }
+
+    /// Drop files checked ahead by a previous batch, their indexes are reused.
+    fn clear(&mut self) {
+        while self.pending > 0 && self.results.recv().is_ok() {
+            self.pending -= 1;
+        }
+        self.ready.clear();
+    }
+
+    /// Wait for a queued file size.
+    fn take(&mut self, i: usize) -> Result<usize, String> {
+        loop {
+            if let Some(result) = self.ready.remove(&i) {
+                return result;
+            }
+            match self.results.recv() {
+                Err(_) => return Err(String::from("readers stopped")),
+                Ok((j, result)) => {
+                    self.pending -= 1;
+                    self.ready.insert(j, result);
                 }
             }
+        }
+    }
+}
 
-            // Flush
-            for i in 0..sinks.len() {
-                try!(sink_files[i].flush());
+/// Read a file lines and return its size, strict validation fails on the first malformed line.
+fn check(path: PathBuf, strict: bool) -> Result<usize, RouterError> {
+    let reader = BufReader::new(try!(File::open(path)));
+
+    let mut size = 0;
+    for line in reader.lines() {
+        let line = try!(line.map_err(RouterError::read));
+        if strict {
+            if let Err(err) = validate(&line) {
+                return Err(RouterError::Parse(From::from(format!("{}: {}", err, line))));
             }
         }
+        size += line.len() + 1;
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io;
+    use std::io::prelude::*;
+    use std::os::unix;
+    use std::path::PathBuf;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    use config;
+    use super::*;
 
-        // Rotate
-        let now = time::now_utc().to_timespec().sec;
+    /// Empty scratch dir, removed first if a previous run left it.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("beamium-router-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sources")).unwrap();
+        fs::create_dir_all(dir.join("sinks")).unwrap();
+        dir
+    }
+
+    /// Load a config with sources and sinks dirs in the scratch dir.
+    /// Extra YAML documents are loaded after the base one.
+    fn load_config(dir: &PathBuf, sinks: &[&str], extra: &str) -> config::Config {
+        let mut yaml = format!("parameters:\n  source-dir: {}\n  sink-dir: {}\nsinks:\n",
+                               dir.join("sources").display(),
+                               dir.join("sinks").display());
         for sink in sinks {
-            let dest_file = dir.join(format!("{}-{}.metrics", sink.name, now));
-            debug!("rotate tmp sink file to {}", format!("{:?}", dest_file));
-            try!(fs::rename(dir.join(format!("{}.tmp", sink.name)), dest_file));
+            yaml.push_str(&format!("  {0}:\n    url: http://127.0.0.1/\n    token: {0}\n", sink));
+        }
+        if !extra.is_empty() {
+            yaml.push_str("---\n");
+            yaml.push_str(extra);
+        }
+        let path = dir.join("config.yaml");
+        File::create(&path).unwrap().write_all(yaml.as_bytes()).unwrap();
+
+        config::load_config(path.to_str().unwrap()).unwrap()
+    }
+
+    /// Route sources dir files once.
+    fn run(config: &config::Config) -> Result<(), RouterError> {
+        let labels = sink_labels(&config.sinks, &config.labels);
+        route(&config.sinks,
+              &config.parameters,
+              &config::source_dirs(config),
+              &labels,
+              &mut false,
+              &mut HashMap::new(),
+              &mut Readers::start(&config.parameters),
+              Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Route a source file and return the metrics rotated for a sink.
+    fn routed(dir: &PathBuf, config: &config::Config, sink: &str, content: &str) -> String {
+        let source = dir.join("sources").join("src-1.metrics");
+        File::create(&source).unwrap().write_all(content.as_bytes()).unwrap();
+        run(config).unwrap();
+        rotated(dir, sink)
+    }
+
+    /// Take the metrics rotated for a sink, oldest first.
+    fn rotated(dir: &PathBuf, sink: &str) -> String {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir.join("sinks"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+                file_name.starts_with(&format!("{}-", sink)) && file_name.ends_with(".metrics")
+            })
+            .collect();
+        paths.sort();
+        let mut metrics = String::new();
+        for path in paths {
+            File::open(&path).unwrap().read_to_string(&mut metrics).unwrap();
+            fs::remove_file(path).unwrap();
         }
+        metrics
+    }
+
+    #[test]
+    fn route_keeps_sources_on_sink_write_failure() {
+        let dir = scratch("write-failure");
+        let config = load_config(&dir, &["a", "b"], "");
+        let source = dir.join("sources").join("src-1.metrics");
+        File::create(&source).unwrap().write_all(b"1// a{} 1\n2// b{} 2\n").unwrap();
 
-        // Delete forwarded data
-        for f in files {
-            debug!("delete source file {}", format!("{:?}", f));
-            try!(fs::remove_file(f));
+        // Writes to the second sink fail once its tmp file is opened
+        unix::fs::symlink("/dev/full", dir.join("sinks").join("b.tmp")).unwrap();
+
+        assert!(run(&config).is_err());
+        assert!(source.exists());
+        let sinks: Vec<PathBuf> = fs::read_dir(dir.join("sinks"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert!(sinks.is_empty(), "sink dir should be empty: {:?}", sinks);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotations_at_the_same_time_do_not_overwrite() {
+        let dir = scratch("rotation");
+        let sinks = dir.join("sinks");
+
+        let first = rotation_path(&sinks, "a", "1000", "metrics");
+        File::create(&first).unwrap().write_all(b"1// a{} 1\n").unwrap();
+        let second = rotation_path(&sinks, "a", "1000", "metrics");
+        File::create(&second).unwrap().write_all(b"2// a{} 2\n").unwrap();
+
+        assert!(first != second);
+        let mut content = String::new();
+        File::open(&first).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "1// a{} 1\n");
+        assert_eq!(second, sinks.join("a-1000.1.metrics"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn readers_are_reused_across_batches() {
+        let dir = scratch("readers");
+        let config = load_config(&dir,
+                                 &["a"],
+                                 "parameters:\n  router-read-parallelism: 4\n  batch-count: 1\n");
+        let mut expected = String::new();
+        for i in 1..7 {
+            let line = format!("{}// m{{}} {}\n", i, i);
+            File::create(dir.join("sources").join(format!("src-{}.metrics", i)))
+                .unwrap()
+                .write_all(line.as_bytes())
+                .unwrap();
+            expected.push_str(&line);
         }
+
+        // Files checked ahead of a batch end are checked again by the next one
+        run(&config).unwrap();
+        assert_eq!(rotated(&dir, "a"), expected);
+        assert_eq!(fs::read_dir(dir.join("sources")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    Ok(())
-}
+    /// Peak resident memory of the process, in kB.
+    fn peak_memory() -> u64 {
+        let mut status = String::new();
+        File::open("/proc/self/status").unwrap().read_to_string(&mut status).unwrap();
+        status.lines()
+            .find(|line| line.starts_with("VmHWM:"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|v| v.parse().ok())
+            .unwrap()
+    }
+
+    #[test]
+    fn large_source_files_are_streamed() {
+        let dir = scratch("large");
+        let config = load_config(&dir, &["a"], "");
+
+        // 32MB of 1kB lines, memory should not follow
+        let line = format!("1// m{{v={}}} 1\n", "x".repeat(1000));
+        let count = 32 * 1024 * 1024 / line.len();
+        {
+            let file = File::create(dir.join("sources").join("src-1.metrics")).unwrap();
+            let mut writer = io::BufWriter::new(file);
+            for _ in 0..count {
+                writer.write_all(line.as_bytes()).unwrap();
+            }
+        }
+
+        let before = peak_memory();
+        run(&config).unwrap();
+        let growth = peak_memory() - before;
+        assert!(growth < 16 * 1024, "peak memory grew by {}kB", growth);
+
+        let rotated: Vec<PathBuf> = fs::read_dir(dir.join("sinks"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(fs::metadata(&rotated[0]).unwrap().len(), (count * line.len()) as u64);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partially_written_source_files_are_not_routed() {
+        let dir = scratch("source-tmp");
+        let config = load_config(&dir, &["a"], "");
+        let partial = dir.join("sources").join("src-2.tmp");
+        File::create(&partial).unwrap().write_all(b"2// b{} 2\n3// c{").unwrap();
+
+        assert_eq!(routed(&dir, &config, "a", "1// a{} 1\n"), "1// a{} 1\n");
+        assert!(partial.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_labels_with_reserved_characters() {
+        let labels = parse_labels(r#"a="x,y",b="{z}",c="k=v",d="q\"uote",e=plain"#).unwrap();
+        assert_eq!(labels,
+                   vec![(String::from("a"), String::from("x,y")),
+                        (String::from("b"), String::from("{z}")),
+                        (String::from("c"), String::from("k=v")),
+                        (String::from("d"), String::from("q\"uote")),
+                        (String::from("e"), String::from("plain"))]);
+    }
+
+    #[test]
+    fn encode_labels_with_reserved_characters() {
+        let line = r#"1// m{a="x,y",b="{z}",c="k=v",d="q\"uote"} 1"#;
+        let encoded = encode_labels(line).unwrap();
+        assert_eq!(encoded, "1// m{a=x%2Cy,b=%7Bz%7D,c=k%3Dv,d=q%22uote} 1");
+
+        // Encoded labels parse back to the original values
+        let metric = parse_metric_line(&encoded).unwrap();
+        let values: Vec<String> = parse_labels(metric.labels.unwrap())
+            .unwrap()
+            .iter()
+            .map(|&(_, ref v)| decode(v))
+            .collect();
+        assert_eq!(values, vec!["x,y", "{z}", "k=v", "q\"uote"]);
+    }
+
+    #[test]
+    fn add_labels_to_metric_without_labels() {
+        assert_eq!(add_labels("1// metric_without_labels 3.14", "host=a").unwrap(),
+                   "1// metric_without_labels{host=a} 3.14");
+        assert_eq!(add_labels("1// metric{} 3.14", "host=a").unwrap(),
+                   "1// metric{host=a} 3.14");
+        assert_eq!(add_labels("1// metric{dc=b} 3.14", "host=a").unwrap(),
+                   "1// metric{host=a,dc=b} 3.14");
+    }
+
+    #[test]
+    fn dots_maps_class_only() {
+        assert_eq!(dots("1// http_requests_total{code=200,path=a_b} 1"),
+                   "1// http.requests.total{code=200,path=a_b} 1");
+        assert_eq!(dots("1// http.requests.total{} 1"), "1// http.requests.total{} 1");
+        assert_eq!(dots("1// up 1"), "1// up{} 1");
+        assert_eq!(dots("# HELP http_requests_total"), "# HELP http_requests_total");
+    }
 
-/// Read a file as String
-fn read(path: PathBuf) -> Result<String, Box<Error>> {
-    let mut file = try!(File::open(path));
+    #[test]
+    fn metric_mapper_is_applied_only_when_enabled() {
+        let line = "1// http_requests_total{path=a_b} 1\n";
 
-    let mut content = String::new();
-    try!(file.read_to_string(&mut content));
+        let dir = scratch("mapper-none");
+        let config = load_config(&dir, &["a"], "");
+        assert_eq!(routed(&dir, &config, "a", line), line);
+        fs::remove_dir_all(&dir).unwrap();
 
-    Ok(content)
+        let dir = scratch("mapper-dots");
+        let config = load_config(&dir, &["a"], "parameters:\n  metric-mapper: dots\n");
+        assert_eq!(routed(&dir, &config, "a", line), "1// http.requests.total{path=a_b} 1\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_metric_line_edge_cases() {
+        // Empty labels
+        let metric = parse_metric_line("1// m{} 1").unwrap();
+        assert_eq!((metric.prefix, metric.class, metric.labels, metric.value),
+                   ("1//", "m", Some(""), "1"));
+
+        // Braces and spaces inside quoted values
+        let metric = parse_metric_line(r#"1// m{a="x} {y",b=z} 2"#).unwrap();
+        assert_eq!((metric.class, metric.labels, metric.value),
+                   ("m", Some(r#"a="x} {y",b=z"#), "2"));
+
+        // Missing value
+        assert!(parse_metric_line("1// m{a=b}").is_err());
+        assert!(parse_metric_line("1// m{a=b}   ").is_err());
+        assert!(parse_metric_line("1// m").is_err());
+
+        // Extra whitespace, and no timestamp
+        let metric = parse_metric_line("  //   m{a=b}    'v'  ").unwrap();
+        assert_eq!((metric.prefix, metric.class, metric.labels, metric.value),
+                   ("//", "m", Some("a=b"), "'v'"));
+
+        // No labels block
+        let metric = parse_metric_line("1// m 3").unwrap();
+        assert_eq!((metric.class, metric.labels, metric.value), ("m", None, "3"));
+
+        // Unterminated labels
+        assert!(parse_metric_line(r#"1// m{a="}" 1"#).is_err());
+    }
+
+    #[test]
+    fn source_name_labels_with_and_without_encoding() {
+        let labels = "labels:\n  origin: \"${source.name} x\"\n";
+
+        let dir = scratch("source-name");
+        let config = load_config(&dir, &["a"], labels);
+        assert_eq!(routed(&dir, &config, "a", "1// m 1\n"), "1// m{origin=src x} 1\n");
+        fs::remove_dir_all(&dir).unwrap();
+
+        let dir = scratch("source-name-encoded");
+        let config = load_config(&dir,
+                                 &["a"],
+                                 &format!("{}parameters:\n  encode-labels: true\n", labels));
+        assert_eq!(routed(&dir, &config, "a", "1// m 1\n"), "1// m{origin=src%20x} 1\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,141 @@
+//! # Scheduler module.
+//!
+//! The Scheduler module run periodic tasks on a fixed pool of threads.
+//! Tasks wait in a timing wheel, so scheduling and expiring them do not depend on their count.
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::VecDeque;
+use std::cmp;
+use std::mem;
+use std::panic;
+use std::time::Duration;
+use time;
+
+/// Wheel tick, in milliseconds.
+const TICK: u64 = 10;
+
+/// Number of wheel slots, a wheel turn spans WHEEL_SIZE ticks.
+const WHEEL_SIZE: u64 = 512;
+
+/// Periodic task.
+/// The task returns the delay before its next run.
+struct Task {
+    /// Next run tick.
+    due: u64,
+    /// Task is dropped once set.
+    sigint: Arc<AtomicBool>,
+    run: Box<FnMut() -> u64 + Send>,
+}
+
+/// Hashed timing wheel, tasks due in a later turn stay in their slot until then.
+struct Wheel {
+    slots: Vec<Vec<Task>>,
+    /// Last expired tick.
+    tick: u64,
+    /// Due tasks waiting for a worker.
+    ready: VecDeque<Task>,
+}
+
+impl Wheel {
+    /// Empty wheel, starting at the current tick.
+    fn new() -> Wheel {
+        Wheel {
+            slots: (0..WHEEL_SIZE).map(|_| Vec::new()).collect(),
+            tick: now(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Add a task, in the ready queue if it is already due.
+    fn insert(&mut self, task: Task) {
+        if task.due <= self.tick {
+            self.ready.push_back(task);
+        } else {
+            self.slots[(task.due % WHEEL_SIZE) as usize].push(task);
+        }
+    }
+
+    /// Move tasks due up to the given tick to the ready queue.
+    /// Each slot is visited at most once, however late the wheel is.
+    fn advance(&mut self, now: u64) {
+        if now <= self.tick {
+            return;
+        }
+
+        for tick in self.tick + 1..self.tick + 1 + cmp::min(now - self.tick, WHEEL_SIZE) {
+            let slot = (tick % WHEEL_SIZE) as usize;
+            let (due, waiting): (Vec<Task>, Vec<Task>) =
+                mem::replace(&mut self.slots[slot], Vec::new())
+                    .into_iter()
+                    .partition(|task| task.due <= now);
+            self.slots[slot] = waiting;
+            self.ready.extend(due);
+        }
+        self.tick = now;
+    }
+}
+
+lazy_static! {
+    /// Tasks waiting for their next run.
+    static ref WHEEL: (Mutex<Wheel>, Condvar) = (Mutex::new(Wheel::new()), Condvar::new());
+}
+
+/// Now in ticks.
+fn now() -> u64 {
+    time::precise_time_ns() / 1000 / 1000 / TICK
+}
+
+/// Schedule a task to run after delay milliseconds, then as long as sigint is not set.
+pub fn schedule(delay: u64, sigint: Arc<AtomicBool>, run: Box<FnMut() -> u64 + Send>) {
+    let &(ref lock, ref cvar) = &*WHEEL;
+    lock.lock().unwrap_or_else(|e| e.into_inner()).insert(Task {
+        due: now() + (delay + TICK - 1) / TICK,
+        sigint: sigint,
+        run: run,
+    });
+    cvar.notify_one();
+}
+
+/// Scheduler thread loop, run due tasks until sigint is set.
+pub fn worker(sigint: Arc<AtomicBool>) {
+    let &(ref lock, ref cvar) = &*WHEEL;
+    loop {
+        // Wait for a due task, expiring the wheel on each tick
+        let mut task = {
+            let mut wheel = lock.lock().unwrap_or_else(|e| e.into_inner());
+            loop {
+                if sigint.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                wheel.advance(now());
+                if !wheel.ready.is_empty() {
+                    break;
+                }
+                wheel = cvar.wait_timeout(wheel, Duration::from_millis(TICK))
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+            }
+            wheel.ready.pop_front().unwrap()
+        };
+
+        if task.sigint.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        // A panicking task is dropped, the worker keeps running the others.
+        // Tasks handle their own panics to be restarted.
+        let delay = {
+            let run = &mut task.run;
+            panic::catch_unwind(panic::AssertUnwindSafe(|| run()))
+        };
+        match delay {
+            Err(_) => crit!("scheduled task panicked"),
+            Ok(delay) => {
+                task.due = now() + (delay + TICK - 1) / TICK;
+                lock.lock().unwrap_or_else(|e| e.into_inner()).insert(task);
+                cvar.notify_one();
+            }
+        }
+    }
+}
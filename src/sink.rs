@@ -1,126 +1,1197 @@
 //! # Sink module.
 //!
-//! The Sink module send metrics to Warp10.
+//! The Sink module send metrics to Warp10, InfluxDB or Graphite.
 use std::thread;
-use std::time::Duration;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use time;
 use std::cmp;
 use std::io::prelude::*;
+use std::io;
 use std::fs;
 use std::fs::File;
 use std::error::Error;
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::fmt;
+use std::collections::HashSet;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::iter;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use slog_scope;
 use hyper;
-use hyper::net::HttpsConnector;
+use hyper::net::{HttpStream, HttpsConnector, NetworkConnector};
+use hyper::status::StatusCode;
+use hyper::header::{Authorization, Basic, Connection, ContentEncoding, Encoding, UserAgent};
+use hyper::client::ProxyConfig;
+use hyper::client::pool::{Pool, Config as PoolConfig};
 use hyper_native_tls::NativeTlsClient;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use nix::libc;
 
 use config;
+use stats;
+use health;
+use source;
+use router;
 
 /// Thread sleeping time.
 const REST_TIME: u64 = 10;
 
+/// Maximum size of Warp10 error bodies kept for logs.
+const MAX_ERROR_BODY: u64 = 4096;
+
+/// Maximum delay(ms) between two retries.
+const MAX_RETRY_DELAY: u64 = 300000;
+
+/// Flush requests count, sinks push their whole backlog without waiting when it changes.
+pub static FLUSH: AtomicUsize = ATOMIC_USIZE_INIT;
+
 /// Sink loop.
 pub fn sink(sink: &config::Sink, parameters: &config::Parameters, sigint: Arc<AtomicBool>) {
+    let mut runner = prepare(sink, parameters);
     loop {
-        let start = time::now_utc();
+        let sleep_time = run(sink, parameters, &mut runner, &sigint);
+        for _ in 0..sleep_time / REST_TIME {
+            thread::sleep(Duration::from_millis(REST_TIME));
+            if sigint.load(Ordering::Relaxed) {
+                return;
+            }
+            if FLUSH.load(Ordering::Relaxed) != runner.flush {
+                break;
+            }
+        }
+    }
+}
+
+/// Sink state kept between runs.
+pub struct Runner {
+    state: State,
+    /// Flush requests count seen by the last run.
+    flush: usize,
+}
+
+/// Prepare a sink before its first run, files claimed by a previous run are released.
+pub fn prepare(sink: &config::Sink, parameters: &config::Parameters) -> Runner {
+    let state = state(sink, parameters);
+
+    if let Err(err) = recover(sink, parameters) {
+        error!("recover fail: {}", err);
+    }
+
+    Runner {
+        state: state,
+        flush: FLUSH.load(Ordering::Relaxed),
+    }
+}
+
+/// Push sink files once, return the delay before the next run.
+pub fn run(sink: &config::Sink,
+           parameters: &config::Parameters,
+           runner: &mut Runner,
+           sigint: &Arc<AtomicBool>)
+           -> u64 {
+    let start = time::now_utc();
+    let flushing = FLUSH.load(Ordering::Relaxed) != runner.flush;
+    runner.flush = FLUSH.load(Ordering::Relaxed);
+
+    let result = match sink.sink_type {
+        config::SinkType::Http => send(sink, parameters, &mut runner.state, sigint),
+        config::SinkType::Null => discard(sink, parameters),
+    };
+    match result {
+        Err(err) => {
+            stats::incr("sink.errors", &format!("sink={}", sink.name), 1);
+            if source::is_timeout(&err) {
+                warn!("post timeout: {}", err)
+            } else {
+                error!("post fail: {}", err)
+            }
+        }
+        Ok(sent) => {
+            health::ready();
+            if flushing {
+                info!("flushed {} files", sent)
+            } else {
+                info!("post success")
+            }
+        }
+    }
+
+    let res = cappe(sink, parameters);
+    if res.is_err() {
+        error!("cappe fail: {}", res.unwrap_err());
+    }
+
+    let elapsed = (time::now_utc() - start).num_milliseconds() as u64;
+    if elapsed > parameters.scan_period {
+        REST_TIME
+    } else {
+        cmp::max(parameters.scan_period - elapsed, REST_TIME)
+    }
+}
+
+/// Push sink files once, until none is left or a push fail.
+pub fn once(sink: &config::Sink, parameters: &config::Parameters, sigint: Arc<AtomicBool>) -> bool {
+    let mut state = state(sink, parameters);
+
+    if let Err(err) = recover(sink, parameters) {
+        error!("recover fail: {}", err);
+    }
+
+    let result = match sink.sink_type {
+        config::SinkType::Http => send(sink, parameters, &mut state, &sigint),
+        config::SinkType::Null => discard(sink, parameters),
+    };
+    if let Err(err) = cappe(sink, parameters) {
+        error!("cappe fail: {}", err);
+    }
+
+    match result {
+        Err(err) => {
+            error!("post fail: {}", err);
+            false
+        }
+        Ok(sent) => {
+            info!("post success, {} files sent", sent);
+            true
+        }
+    }
+}
+
+/// Build a sink thread state.
+fn state(sink: &config::Sink, parameters: &config::Parameters) -> State {
+    // The HTTP client only speaks HTTP/1.1
+    if sink.http_version == config::HttpVersion::Http2 {
+        warn!("http2 is not supported, fall back to http1");
+    }
+
+    State {
+        url: 0,
+        client: Arc::new(client(sink, parameters)),
+        token: None,
+        graphite: None,
+        timeout: request_timeout(sink, parameters),
+        user_agent: sink.user_agent.clone().unwrap_or(parameters.user_agent.clone()),
+        bucket: sink.max_rate.map(|rate| Arc::new(Mutex::new(Bucket::new(rate)))),
+    }
+}
+
+/// Sink thread state.
+struct State {
+    /// Index of the url to use first, the last healthy one.
+    url: usize,
+    /// HTTP client, reused across pushes to keep connections alive.
+    client: Arc<hyper::Client>,
+    /// Token read from the token file, with the file modification time.
+    token: Option<(SystemTime, String)>,
+    /// Graphite connection, with its url.
+    graphite: Option<(String, TcpStream)>,
+    /// Network timeout.
+    timeout: Duration,
+    /// User-Agent sent with pushes.
+    user_agent: String,
+    /// Rate limiter, shared by the sink push workers.
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
 
-        match send(sink, parameters) {
-            Err(err) => error!("post fail: {}", err),
-            Ok(_) => info!("post success"),
+/// Token bucket pacing pushed datapoints.
+/// Tokens may go negative, pushes then wait until the debt is paid back.
+struct Bucket {
+    /// Datapoints per second, also the bucket capacity.
+    rate: u64,
+    tokens: f64,
+    /// Last refill, in milliseconds.
+    last: u64,
+    /// Datapoints taken since the window start, for the rate metric.
+    sent: u64,
+    /// Rate metric window start, in milliseconds.
+    window: u64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        let now = now();
+        Bucket {
+            rate: rate,
+            tokens: rate as f64,
+            last: now,
+            sent: 0,
+            window: now,
         }
+    }
 
-        let res = cappe(sink, parameters);
-        if res.is_err() {
-            error!("cappe fail: {}", res.unwrap_err());
+    /// Take datapoints from the bucket, return the time to wait before sending them in ms.
+    fn take(&mut self, points: u64, sink: &str) -> u64 {
+        let now = now();
+        let refill = (now - self.last) as f64 * self.rate as f64 / 1000.0;
+        self.tokens = (self.tokens + refill).min(self.rate as f64) - points as f64;
+        self.last = now;
+
+        // Expose the pushed rate every second
+        self.sent += points;
+        if now - self.window >= 1000 {
+            stats::set("sink.rate",
+                       &format!("sink={}", sink),
+                       self.sent * 1000 / (now - self.window));
+            self.sent = 0;
+            self.window = now;
         }
 
-        let elapsed = (time::now_utc() - start).num_milliseconds() as u64;
-        let sleep_time = if elapsed > parameters.scan_period {
-            REST_TIME
+        if self.tokens >= 0.0 {
+            0
         } else {
-            cmp::max(parameters.scan_period - elapsed, REST_TIME)
-        };
-        for _ in 0..sleep_time / REST_TIME {
-            thread::sleep(Duration::from_millis(REST_TIME));
-            if sigint.load(Ordering::Relaxed) {
-                return;
-            }
+            (-self.tokens * 1000.0 / self.rate as f64) as u64
         }
     }
 }
 
+/// Now in milliseconds.
+fn now() -> u64 {
+    time::precise_time_ns() / 1000 / 1000
+}
+
+/// Non success Warp10 response.
+#[derive(Debug)]
+struct StatusError {
+    status: StatusCode,
+    /// Response body, capped to MAX_ERROR_BODY bytes.
+    body: String,
+    /// Retry-After header, in seconds.
+    retry_after: Option<u64>,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "non 200 received: {}: {}", self.status, self.body.trim())
+    }
+}
+
+impl Error for StatusError {
+    fn description(&self) -> &str {
+        "non 200 received"
+    }
+}
+
 /// Send sink metrics to Warp10.
-fn send(sink: &config::Sink, parameters: &config::Parameters) -> Result<(), Box<Error>> {
-    debug!("post {}", &sink.url);
+/// Files are only deleted once Warp10 accepted them, otherwise they are kept for the next cycle.
+/// Up to parallelism batches are pushed concurrently.
+/// Return the number of sent files.
+fn send(sink: &config::Sink,
+        parameters: &config::Parameters,
+        state: &mut State,
+        sigint: &Arc<AtomicBool>)
+        -> Result<u64, Box<Error>> {
+    debug!("post {}", &sink.urls[state.url]);
+    let mut sent = 0;
 
     loop {
-        let entries = try!(files(&parameters.sink_dir, &sink.name));
-        let mut files = Vec::with_capacity(parameters.batch_count as usize);
-        let mut metrics = String::new();
-
-        // Load metrics
-        let mut batch_size = 0;
-        for (i, entry) in entries.iter().enumerate() {
-            // Split metrics in capped batch
-            if i > parameters.batch_count as usize || batch_size > parameters.batch_size as usize {
+        // Claim batches, ordered pushes are never concurrent
+        let parallelism = if sink.preserve_order {
+            1
+        } else {
+            sink.parallelism
+        };
+        let mut batches: Vec<(Vec<PathBuf>, String)> = Vec::with_capacity(parallelism as usize);
+        for _ in 0..parallelism {
+            match claim(sink, parameters) {
+                Err(err) => {
+                    for &(ref files, _) in &batches {
+                        release(files);
+                    }
+                    return Err(err);
+                }
+                Ok(None) => break,
+                Ok(Some(batch)) => batches.push(batch),
+            }
+        }
+
+        // Nothing to do
+        if batches.is_empty() {
+            break;
+        }
+
+        // Send metrics
+        let results = if batches.len() == 1 {
+            let (files, metrics) = batches.pop().unwrap();
+            vec![(files, push_chunks(sink, state, &metrics, sigint))]
+        } else {
+            push_parallel(sink, state, batches, sigint)
+        };
+
+        // Every batch is settled, so none stays claimed after a failure
+        let mut failure = None;
+        for (files, result) in results {
+            match settle(sink, parameters, files, result) {
+                Ok(n) => sent += n,
+                Err(err) => failure = failure.or(Some(err)),
+            }
+        }
+
+        if let Some(err) = failure {
+            return Err(err);
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Delete pushed files, or quarantine rejected ones, or release them after a failed push.
+/// Files still claimed on error are released. Return the number of sent files.
+fn settle(sink: &config::Sink,
+          parameters: &config::Parameters,
+          files: Vec<PathBuf>,
+          result: Result<(), Box<Error>>)
+          -> Result<u64, Box<Error>> {
+    let err = match result {
+        Ok(_) => {
+            // Delete sended data
+            for (i, f) in files.iter().enumerate() {
+                debug!("delete sink file {}", format!("{:?}", f));
+                if let Err(err) = fs::remove_file(f) {
+                    release(&files[i..]);
+                    return Err(From::from(err));
+                }
+                stats::incr("sink.files.sent", &format!("sink={}", sink.name), 1);
+            }
+            return Ok(files.len() as u64);
+        }
+        Err(err) => err,
+    };
+
+    let rejected = err.downcast_ref::<StatusError>()
+        .map_or(false,
+                |err| parameters.dead_letter_status.contains(&err.status.to_u16()));
+    match parameters.dead_letter_dir {
+        Some(ref dir) if rejected => {
+            // Quarantine rejected data so it does not block the queue
+            for (i, f) in files.iter().enumerate() {
+                warn!("move rejected file {:?} to dead letter dir: {}", f, err);
+                let dest_file = Path::new(dir).join(release_path(f)
+                    .file_name()
+                    .unwrap_or_default());
+                if let Err(err) = move_file(f, &dest_file) {
+                    release(&files[i..]);
+                    return Err(From::from(err));
+                }
+            }
+            Ok(0)
+        }
+        _ => {
+            release(&files);
+            Err(err)
+        }
+    }
+}
+
+/// Release claimed files so the next push takes them again.
+fn release(files: &[PathBuf]) {
+    for f in files {
+        if let Err(err) = fs::rename(f, release_path(f)) {
+            warn!("fail to release sink file {:?}: {}", f, err);
+        }
+    }
+}
+
+/// Delete sink files without pushing them, for null sinks.
+/// Return the number of deleted files.
+fn discard(sink: &config::Sink, parameters: &config::Parameters) -> Result<u64, Box<Error>> {
+    let mut discarded = 0;
+    for entry in try!(files(&parameters.sink_dir, &sink.name)) {
+        debug!("discard sink file {:?}", entry.path());
+        try!(fs::remove_file(entry.path()));
+        discarded += 1;
+    }
+    stats::incr("sink.files.discarded", &format!("sink={}", sink.name), discarded);
+
+    Ok(discarded)
+}
+
+/// Claim a batch of files by renaming them, so they are never pushed twice.
+/// Files claimed before a failure are released.
+fn claim(sink: &config::Sink,
+         parameters: &config::Parameters)
+         -> Result<Option<(Vec<PathBuf>, String)>, Box<Error>> {
+    let mut files = Vec::with_capacity(parameters.batch_count as usize);
+    let metrics = match take(sink, parameters, &mut files) {
+        Err(err) => {
+            release(&files);
+            return Err(err);
+        }
+        Ok(v) => v,
+    };
+
+    if metrics.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some((files, serialize(sink, parameters, metrics))))
+}
+
+/// Claim files up to the batch limits, pushing them to `claimed`, and return their metrics.
+fn take(sink: &config::Sink,
+        parameters: &config::Parameters,
+        claimed: &mut Vec<PathBuf>)
+        -> Result<String, Box<Error>> {
+    let mut entries = try!(files(&parameters.sink_dir, &sink.name));
+    // Oldest first, file names do not sort rotation collisions after their first file
+    if sink.preserve_order {
+        entries.sort_by_key(|entry| {
+            rotation(entry.file_name().to_str().unwrap_or(""), &sink.name)
+        });
+    }
+    let mut metrics = String::new();
+
+    // Coalesced files never exceed a push, a single file still may
+    let max_bytes = match (sink.coalesce_max_bytes, sink.max_push_size) {
+        (Some(max), Some(push)) => Some(cmp::min(max, push)),
+        (max, push) => max.or(push),
+    };
+
+    // Load metrics
+    let mut batch_size = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        // Split metrics in capped batch
+        if i > parameters.batch_count as usize || batch_size > parameters.batch_size as usize {
+            break;
+        }
+        if !sink.coalesce && !claimed.is_empty() {
+            break;
+        }
+
+        debug!("open sink file {:?}", entry.path());
+        let file = match read(entry.path()) {
+            Err(_) => continue,
+            Ok(v) => v,
+        };
+        let file = if sink.validate {
+            let valid = try!(reject(sink, parameters, &entry.path(), file));
+            // Every line was rejected, nothing is left to push
+            if valid.is_empty() {
+                debug!("delete sink file {:?}", entry.path());
+                try!(fs::remove_file(entry.path()));
+                continue;
+            }
+            valid
+        } else {
+            file
+        };
+        if let Some(max) = max_bytes {
+            if !claimed.is_empty() && (batch_size + file.len()) as u64 > max {
                 break;
             }
+        }
+
+        let processing = processing_path(&entry.path());
+        try!(fs::rename(entry.path(), &processing));
+        claimed.push(processing);
+        batch_size += file.len();
+        metrics.push_str(&file);
+        metrics.push_str("\n");
+    }
+
+    Ok(metrics)
+}
+
+/// Drop lines which would get a whole batch rejected by Warp10.
+/// Dropped lines are kept in the dead letter dir, each after a comment giving its file and error.
+fn reject(sink: &config::Sink,
+          parameters: &config::Parameters,
+          path: &Path,
+          metrics: String)
+          -> Result<String, Box<Error>> {
+    let mut valid = String::with_capacity(metrics.len());
+    let mut rejected = String::new();
+    for line in metrics.lines() {
+        match router::validate(line) {
+            Err(err) => {
+                warn!("bad row {} in {:?}: {}", line, path, err);
+                stats::incr("sink.dropped", &format!("sink={},reason=invalid", sink.name), 1);
+                rejected.push_str(&format!("# {:?}: {}\n{}\n", path, err, line));
+            }
+            Ok(_) => {
+                valid.push_str(line);
+                valid.push_str("\n");
+            }
+        }
+    }
+
+    if let Some(ref dir) = parameters.dead_letter_dir {
+        if !rejected.is_empty() {
+            let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+            file_name.push(".invalid");
+            let dest_file = Path::new(dir).join(file_name);
+            debug!("write invalid rows to {:?}", dest_file);
+            let mut file = try!(create_file(&dest_file, parameters));
+            try!(file.write_all(rejected.as_bytes()));
+            try!(file.flush());
+        }
+    }
+
+    Ok(valid)
+}
+
+/// Convert metrics to the sink format.
+fn serialize(sink: &config::Sink, parameters: &config::Parameters, metrics: String) -> String {
+    match sink.format {
+        config::SinkFormat::Warp10 => metrics,
+        config::SinkFormat::Influx => {
+            let mut body = String::with_capacity(metrics.len());
+            for line in metrics.lines().filter(|line| !line.is_empty()) {
+                match influx(line, &parameters.timestamp_unit) {
+                    Err(err) => {
+                        warn!("bad row {}: {}", line, err);
+                        stats::incr("sink.dropped",
+                                    &format!("sink={},reason=format", sink.name),
+                                    1);
+                    }
+                    Ok(v) => {
+                        body.push_str(&v);
+                        body.push_str("\n");
+                    }
+                }
+            }
+            body
+        }
+        config::SinkFormat::Graphite => {
+            let body = String::with_capacity(metrics.len());
+            metrics.lines()
+                .filter(|line| !line.is_empty())
+                .fold(body, |mut body, line| {
+                    match graphite(line, &parameters.timestamp_unit) {
+                        Err(err) => {
+                            warn!("bad row {}: {}", line, err);
+                            stats::incr("sink.dropped",
+                                        &format!("sink={},reason=format", sink.name),
+                                        1);
+                        }
+                        Ok(v) => {
+                            body.push_str(&v);
+                            body.push_str("\n");
+                        }
+                    }
+                    body
+                })
+        }
+    }
+}
+
+/// Convert a Warp10 metric to InfluxDB line protocol.
+/// `TS/LAT:LON/ELEV class{labels} value` becomes `class,labels value=value TS`.
+fn influx(line: &str, unit: &config::TimestampUnit) -> Result<String, Box<Error>> {
+    let metric = try!(router::parse_metric_line(line));
+    let timestamp = metric.prefix.split('/').next().unwrap_or("");
+
+    let mut result = influx_escape(&router::decode(metric.class), ", ");
+    for (k, v) in try!(router::parse_labels(metric.labels.unwrap_or(""))) {
+        let (k, v) = (router::decode(&k), router::decode(&v));
+        // InfluxDB rejects empty tag values
+        if k.is_empty() || v.is_empty() {
+            continue;
+        }
+        result.push(',');
+        result.push_str(&influx_escape(&k, ",= "));
+        result.push('=');
+        result.push_str(&influx_escape(&v, ",= "));
+    }
 
-            debug!("open sink file {:?}", entry.path());
-            let file = match read(entry.path()) {
-                Err(_) => continue,
-                Ok(v) => v,
+    let value = match metric.value {
+        "T" | "true" => String::from("true"),
+        "F" | "false" => String::from("false"),
+        v if v.starts_with('\'') => {
+            let v = router::decode(v.trim_matches('\''));
+            format!("\"{}\"", influx_escape(&v, "\"\\"))
+        }
+        v => String::from(v),
+    };
+    result.push_str(" value=");
+    result.push_str(&value);
+
+    // Let InfluxDB stamp metrics without timestamp
+    if !timestamp.is_empty() {
+        let timestamp = try!(i64::from_str_radix(timestamp, 10));
+        result.push_str(&format!(" {}", unit.to_nanos(timestamp)));
+    }
+
+    Ok(result)
+}
+
+/// Backslash escape InfluxDB line protocol special characters.
+fn influx_escape(s: &str, special: &str) -> String {
+    s.chars().fold(String::new(), |mut acc, c| {
+        if special.contains(c) {
+            acc.push('\\');
+        }
+        acc.push(c);
+        acc
+    })
+}
+
+/// Convert a Warp10 metric to Graphite plaintext protocol.
+/// `TS// class{k=v} value` becomes `class.v value TS`, labels values are appended to the path
+/// in labels keys order and the timestamp is converted to seconds.
+fn graphite(line: &str, unit: &config::TimestampUnit) -> Result<String, Box<Error>> {
+    let metric = try!(router::parse_metric_line(line));
+    let timestamp = metric.prefix.split('/').next().unwrap_or("");
+
+    let parsed = try!(router::parse_labels(metric.labels.unwrap_or("")));
+    let mut labels: Vec<(String, String)> = parsed.iter()
+        .map(|&(ref k, ref v)| (router::decode(k), router::decode(v)))
+        .filter(|&(_, ref v)| !v.is_empty())
+        .collect();
+    labels.sort();
+
+    let mut path = router::decode(metric.class).replace(|c: char| c.is_whitespace(), "_");
+    for (_, v) in labels {
+        path.push('.');
+        path.push_str(&v.replace(|c: char| c == '.' || c.is_whitespace(), "_"));
+    }
+
+    let value = match metric.value {
+        "T" | "true" => "1",
+        "F" | "false" => "0",
+        v if v.starts_with('\'') => return Err(From::from("string values are not supported")),
+        v => v,
+    };
+
+    let timestamp = if timestamp.is_empty() {
+        time::now_utc().to_timespec().sec
+    } else {
+        unit.to_nanos(try!(i64::from_str_radix(timestamp, 10))) / (1000 * 1000 * 1000)
+    };
+
+    Ok(format!("{} {} {}", path, value, timestamp))
+}
+
+/// Push batches concurrently, one thread per batch.
+fn push_parallel(sink: &config::Sink,
+                 state: &mut State,
+                 batches: Vec<(Vec<PathBuf>, String)>,
+                 sigint: &Arc<AtomicBool>)
+                 -> Vec<(Vec<PathBuf>, Result<(), Box<Error>>)> {
+    let handles: Vec<_> = batches.into_iter()
+        .map(|(files, metrics)| {
+            let (sink, sigint, logger) = (sink.clone(), sigint.clone(), slog_scope::logger());
+            let mut worker_state = State {
+                url: state.url,
+                client: state.client.clone(),
+                token: state.token.clone(),
+                graphite: None,
+                timeout: state.timeout,
+                user_agent: state.user_agent.clone(),
+                bucket: state.bucket.clone(),
+            };
+            let handle = thread::spawn(move || -> Result<usize, Box<Error + Send + Sync>> {
+                slog_scope::scope(logger, || {
+                    match push_chunks(&sink, &mut worker_state, &metrics, &sigint) {
+                        Ok(_) => Ok(worker_state.url),
+                        // Keep status errors for dead letter handling
+                        Err(err) => {
+                            match err.downcast::<StatusError>() {
+                                Ok(err) => Err(err as Box<Error + Send + Sync>),
+                                Err(err) => Err(From::from(err.to_string())),
+                            }
+                        }
+                    }
+                })
+            });
+            (files, handle)
+        })
+        .collect();
+
+    handles.into_iter()
+        .map(|(files, handle)| {
+            let result = match handle.join() {
+                Err(_) => Err(From::from("push worker panicked")),
+                Ok(Err(err)) => Err(err as Box<Error>),
+                Ok(Ok(url)) => {
+                    state.url = url;
+                    Ok(())
+                }
             };
+            (files, result)
+        })
+        .collect()
+}
 
-            files.push(entry.path());
-            batch_size += file.len();
-            metrics.push_str(&file);
-            metrics.push_str("\n");
+/// Move a file, fall back to copy and remove when crossing filesystems.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref err) if err.raw_os_error() == Some(libc::EXDEV) => {
+            debug!("copy {:?} to {:?} across filesystems", from, to);
+            try!(fs::copy(from, to));
+            try!(try!(File::open(to)).sync_all());
+            fs::remove_file(from)
         }
+        res => res,
+    }
+}
 
-        // Nothing to do
-        if metrics.len() == 0 {
-            break;
+/// Create or truncate a file, with parameters.file-mode permissions when it is created.
+pub fn create_file(path: &Path, parameters: &config::Parameters) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if let Some(mode) = parameters.file_mode {
+        options.mode(mode);
+    }
+    options.open(path)
+}
+
+/// Create a dir and its parents, with parameters.dir-mode permissions when they are created.
+pub fn create_dir(dir: &str, parameters: &config::Parameters) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    if let Some(mode) = parameters.dir_mode {
+        builder.mode(mode);
+    }
+    builder.create(dir)
+}
+
+/// Name of a claimed file.
+fn processing_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".processing");
+    path.with_file_name(file_name)
+}
+
+/// Name of a released claimed file.
+fn release_path(path: &Path) -> PathBuf {
+    path.with_extension("")
+}
+
+/// Release files claimed by a previous run.
+fn recover(sink: &config::Sink, parameters: &config::Parameters) -> Result<(), Box<Error>> {
+    for entry in try!(fs::read_dir(&parameters.sink_dir)) {
+        let path = try!(entry).path();
+        if path.extension() != Some(OsStr::new("processing")) {
+            continue;
+        }
+        let file_name = String::from(release_path(&path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(""));
+        if !owns(&file_name, &sink.name) {
+            continue;
         }
 
-        // Send metrics
-        let ssl = NativeTlsClient::new().unwrap();
-        let connector = HttpsConnector::new(ssl);
-        let mut client = hyper::Client::with_connector(connector);
-        client.set_write_timeout(Some(Duration::from_secs(parameters.timeout)));
-        client.set_read_timeout(Some(Duration::from_secs(parameters.timeout)));
+        debug!("release sink file {:?}", path);
+        try!(fs::rename(&path, release_path(&path)));
+    }
+
+    Ok(())
+}
+
+/// Push metrics in chunks capped by the sink max push size.
+/// Fail as soon as a chunk fail, so files are kept for retry as a whole.
+fn push_chunks(sink: &config::Sink,
+               state: &mut State,
+               metrics: &str,
+               sigint: &Arc<AtomicBool>)
+               -> Result<(), Box<Error>> {
+    let max = sink.max_push_size.map_or(metrics.len(), |max| max as usize);
+    for chunk in chunks(metrics, max) {
+        try!(push_retry(sink, state, chunk, sigint));
+    }
+
+    // Attributes are best effort, metrics are already accepted
+    if !sink.meta_urls.is_empty() {
+        if let Err(err) = push_meta(sink, state, metrics) {
+            warn!("post meta fail: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Post sink attributes of every GTS of the metrics to Warp10 meta endpoint.
+fn push_meta(sink: &config::Sink, state: &mut State, metrics: &str) -> Result<(), Box<Error>> {
+    let attributes = sink.attributes
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    let body = meta(metrics, &attributes);
+
+    let token = try!(token(sink, state));
+    let url = &sink.meta_urls[state.url % sink.meta_urls.len()];
+    debug!("post meta {}", url);
+    post(sink, state, url, &token, &try!(compress(sink, &body)))
+}
+
+/// Build a Warp10 meta body, a line with the attributes for each GTS of the metrics.
+fn meta(metrics: &str, attributes: &str) -> String {
+    let mut series = HashSet::new();
+    for line in metrics.lines() {
+        if let Ok(metric) = router::parse_metric_line(line) {
+            series.insert(format!("{}{{{}}}", metric.class, metric.labels.unwrap_or("")));
+        }
+    }
+
+    series.iter().fold(String::new(), |acc, gts| acc + gts + "{" + attributes + "}\n")
+}
+
+/// Split metrics in chunks of at most max bytes on line boundaries.
+/// A line bigger than max is sent on its own.
+fn chunks(metrics: &str, max: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let (mut start, mut end) = (0, 0);
+
+    let ends = metrics.match_indices('\n').map(|(i, _)| i + 1).chain(iter::once(metrics.len()));
+    for line_end in ends {
+        if line_end == end {
+            continue;
+        }
+        if line_end - start > max && end > start {
+            chunks.push(&metrics[start..end]);
+            start = end;
+        }
+        end = line_end;
+    }
+    if end > start {
+        chunks.push(&metrics[start..end]);
+    }
+
+    chunks
+}
+
+/// Push metrics, retrying with exponential backoff on server and network errors.
+/// Retries are done in the calling thread, so a batch never has more than one push in flight.
+fn push_retry(sink: &config::Sink,
+              state: &mut State,
+              metrics: &str,
+              sigint: &Arc<AtomicBool>)
+              -> Result<(), Box<Error>> {
+    let mut delay = sink.retry_delay;
+    let mut attempt = 0;
+
+    // Compress once for every attempt
+    let body = try!(compress(sink, metrics));
+    let points = metrics.lines().count() as u64;
+
+    loop {
+        try!(pace(sink, state, points, sigint));
+        let err = match push(sink, state, metrics, &body) {
+            Ok(v) => return Ok(v),
+            Err(err) => err,
+        };
+
+        // Client errors will not get better, unless the token file was rotated or throttled
+        let retryable = err.downcast_ref::<StatusError>()
+            .map_or(true, |err| {
+                err.status.is_server_error() || err.status == StatusCode::TooManyRequests ||
+                (err.status == StatusCode::Forbidden && sink.token_file.is_some())
+            });
+        if !retryable || attempt >= sink.retry {
+            return Err(err);
+        }
+
+        // Warp10 may tell how long to back off
+        let wait = err.downcast_ref::<StatusError>()
+            .and_then(|err| err.retry_after)
+            .map_or(delay, |seconds| seconds * 1000);
+
+        attempt += 1;
+        debug!("post attempt {} fail: {}, retry in {}ms", attempt, err, wait);
+        for _ in 0..wait / REST_TIME {
+            thread::sleep(Duration::from_millis(REST_TIME));
+            if sigint.load(Ordering::Relaxed) {
+                return Err(err);
+            }
+        }
+        delay = cmp::min(delay.saturating_mul(2), MAX_RETRY_DELAY);
+    }
+}
+
+/// Wait until the sink rate limit allows to push points.
+fn pace(sink: &config::Sink,
+        state: &State,
+        points: u64,
+        sigint: &Arc<AtomicBool>)
+        -> Result<(), Box<Error>> {
+    let wait = match state.bucket {
+        None => return Ok(()),
+        Some(ref bucket) => {
+            bucket.lock().unwrap_or_else(|e| e.into_inner()).take(points, &sink.name)
+        }
+    };
 
-        let mut headers = hyper::header::Headers::new();
-        headers.set_raw(sink.token_header.clone(), vec![sink.token.clone().into()]);
+    if wait > 0 {
+        debug!("rate limited, wait {}ms", wait);
+    }
+    for _ in 0..wait / REST_TIME {
+        thread::sleep(Duration::from_millis(REST_TIME));
+        if sigint.load(Ordering::Relaxed) {
+            return Err(From::from("interrupted while rate limited"));
+        }
+    }
+
+    Ok(())
+}
 
-        debug!("post metrics");
-        let request = client.post(&sink.url).headers(headers).body(&metrics);
-        let mut res = try!(request.send());
-        if !res.status.is_success() {
-            let mut body = String::new();
-            try!(res.read_to_string(&mut body));
-            debug!("data {}", &body);
+/// Push metrics to Warp10.
+/// Urls are tried in order, starting from the last healthy one, until one accept metrics.
+fn push(sink: &config::Sink,
+        state: &mut State,
+        metrics: &str,
+        body: &[u8])
+        -> Result<(), Box<Error>> {
+    let token = try!(token(sink, state));
 
-            return Err(From::from("non 200 received"));
+    let mut last_err = None;
+    for i in 0..sink.urls.len() {
+        let index = (state.url + i) % sink.urls.len();
+        let url = &sink.urls[index];
+
+        let result = match sink.format {
+            config::SinkFormat::Graphite => write_graphite(state, url, metrics),
+            _ => post(sink, state, url, &token, body),
+        };
+        let err = match result {
+            Ok(_) => {
+                if index != state.url {
+                    warn!("failover to {}", url);
+                    state.url = index;
+                }
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        // Token may have been rotated, read it again on next push
+        let forbidden = err.downcast_ref::<StatusError>()
+            .map_or(false, |err| err.status == StatusCode::Forbidden);
+        if forbidden {
+            state.token = None;
         }
 
-        // Delete sended data
-        for f in files {
-            debug!("delete sink file {}", format!("{:?}", f));
-            try!(fs::remove_file(f));
+        // Only fail over on server and network errors
+        let failover = err.downcast_ref::<StatusError>()
+            .map_or(true, |err| err.status.is_server_error());
+        if !failover {
+            return Err(err);
+        }
+        if sink.urls.len() > 1 {
+            warn!("post to {} fail: {}", url, err);
         }
+        last_err = Some(err);
     }
 
+    Err(last_err.unwrap_or_else(|| From::from("no url")))
+}
+
+/// Gzip a push body if the sink compress requests.
+fn compress<'a>(sink: &config::Sink, metrics: &'a str) -> Result<Cow<'a, [u8]>, Box<Error>> {
+    if sink.request_compression == config::Compression::None {
+        return Ok(Cow::Borrowed(metrics.as_bytes()));
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::Default);
+    try!(encoder.write_all(metrics.as_bytes()));
+    let body = try!(encoder.finish());
+    debug!("gzip {} bytes to {} bytes, ratio {:.2}",
+           metrics.len(),
+           body.len(),
+           metrics.len() as f64 / cmp::max(body.len(), 1) as f64);
+
+    Ok(Cow::Owned(body))
+}
+
+/// Post metrics to a Warp10 url.
+fn post(sink: &config::Sink,
+        state: &State,
+        url: &str,
+        token: &str,
+        body: &[u8])
+        -> Result<(), Box<Error>> {
+    let mut headers = hyper::header::Headers::new();
+    headers.set(UserAgent(state.user_agent.clone()));
+    for (k, v) in &sink.headers {
+        headers.set_raw(k.clone(), vec![v.as_bytes().to_vec()]);
+    }
+    headers.set_raw(sink.token_header.clone(), vec![token.as_bytes().to_vec()]);
+    if !sink.keep_alive {
+        headers.set(Connection::close());
+    }
+    if sink.request_compression == config::Compression::Gzip {
+        headers.set(ContentEncoding(vec![Encoding::Gzip]));
+    }
+    // Inside a CONNECT tunnel the header would reach Warp10 instead of the proxy
+    if url.starts_with("http:") {
+        if let Some(auth) = proxy_auth(sink) {
+            headers.set_raw("Proxy-Authorization", vec![auth.into_bytes()]);
+        }
+    }
+
+    debug!("post metrics");
+    let request = state.client.post(url).headers(headers).body(body);
+    let mut res = try!(request.send());
+    if !res.status.is_success() {
+        let mut body = Vec::new();
+        try!(res.by_ref().take(MAX_ERROR_BODY).read_to_end(&mut body));
+        try!(io::copy(&mut res, &mut io::sink()));
+
+        // Only delay seconds are supported, not HTTP dates
+        let retry_after = res.headers
+            .get_raw("Retry-After")
+            .and_then(|values| values.first())
+            .and_then(|value| String::from_utf8_lossy(value).trim().parse::<u64>().ok());
+
+        return Err(Box::new(StatusError {
+            status: res.status,
+            body: String::from_utf8_lossy(&body).into_owned(),
+            retry_after: retry_after,
+        }));
+    }
+
+    // Drain body so the connection goes back to the pool
+    try!(io::copy(&mut res, &mut io::sink()));
+
     Ok(())
 }
 
+/// Write metrics to a Graphite url, the connection is kept open between pushes.
+/// On error the connection is dropped, so it is opened again on next push.
+fn write_graphite(state: &mut State, url: &str, metrics: &str) -> Result<(), Box<Error>> {
+    let connected = match state.graphite {
+        Some((ref addr, _)) => addr == url,
+        None => false,
+    };
+    if !connected {
+        let parsed = try!(hyper::Url::parse(url));
+        let host = try!(parsed.host_str().ok_or("no host"));
+        let port = try!(parsed.port().ok_or("no port"));
+        debug!("connect to {}", url);
+        let stream = try!(TcpStream::connect((source::unbracket(host), port)));
+        try!(stream.set_write_timeout(Some(state.timeout)));
+        state.graphite = Some((String::from(url), stream));
+    }
+
+    debug!("write metrics");
+    let result = {
+        let stream = &mut state.graphite.as_mut().unwrap().1;
+        stream.write_all(metrics.as_bytes()).and_then(|_| stream.flush())
+    };
+    if result.is_err() {
+        state.graphite = None;
+    }
+    try!(result);
+
+    Ok(())
+}
+
+/// Get sink token.
+/// Token file is only read again when modified.
+fn token(sink: &config::Sink, state: &mut State) -> Result<String, Box<Error>> {
+    let path = match sink.token_file {
+        None => return Ok(sink.token.clone().unwrap_or_default()),
+        Some(ref path) => path,
+    };
+
+    let modified = try!(try!(fs::metadata(path)).modified());
+    if let Some((ref cached, ref token)) = state.token {
+        if *cached == modified {
+            return Ok(token.clone());
+        }
+    }
+
+    debug!("read token file {}", path);
+    let mut file = try!(File::open(path));
+    let mut token = String::new();
+    try!(file.read_to_string(&mut token));
+    let token = String::from(token.trim());
+    state.token = Some((modified, token.clone()));
+
+    Ok(token)
+}
+
+/// Proxy basic authentication header value, from proxy url credentials.
+fn proxy_auth(sink: &config::Sink) -> Option<String> {
+    let url = match sink.proxy.as_ref().and_then(|proxy| hyper::Url::parse(proxy).ok()) {
+        Some(url) => url,
+        None => return None,
+    };
+    if url.username().is_empty() {
+        return None;
+    }
+
+    let auth = Authorization(Basic {
+        username: String::from(url.username()),
+        password: url.password().map(String::from),
+    });
+    let mut headers = hyper::header::Headers::new();
+    headers.set(auth);
+    headers.get_raw("Authorization")
+        .and_then(|raw| raw.first())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// Build sink HTTP client.
+/// Proxied clients always use a connection pool.
+fn client(sink: &config::Sink, parameters: &config::Parameters) -> hyper::Client {
+    let ssl = NativeTlsClient::new().unwrap();
+    let connector = TimeoutConnector { timeout: Duration::from_millis(sink.connect_timeout) };
+    let proxy = sink.proxy.as_ref().and_then(|proxy| hyper::Url::parse(proxy).ok());
+    let mut client = if let Some(proxy) = proxy {
+        let host = String::from(proxy.host_str().unwrap_or_default());
+        let port = proxy.port_or_known_default().unwrap_or(80);
+        let config = ProxyConfig::new(proxy.scheme(), host, port, connector, ssl);
+        hyper::Client::with_proxy_config(config)
+    } else if sink.keep_alive {
+        let connector = HttpsConnector::with_connector(ssl, connector);
+        let config = PoolConfig { max_idle: sink.parallelism as usize };
+        hyper::Client::with_connector(Pool::with_connector(config, connector))
+    } else {
+        hyper::Client::with_connector(HttpsConnector::with_connector(ssl, connector))
+    };
+    client.set_write_timeout(Some(request_timeout(sink, parameters)));
+    client.set_read_timeout(Some(request_timeout(sink, parameters)));
+
+    client
+}
+
+/// Sink read and write timeout.
+fn request_timeout(sink: &config::Sink, parameters: &config::Parameters) -> Duration {
+    sink.request_timeout
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(parameters.timeout))
+}
+
+/// HTTP connector bounding connection time.
+struct TimeoutConnector {
+    timeout: Duration,
+}
+
+impl NetworkConnector for TimeoutConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        if scheme != "http" && scheme != "https" {
+            return Err(hyper::Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                       "invalid scheme for http")));
+        }
+
+        // Try every resolved address in turn, IPv6 literals come bracketed
+        let mut last_err = io::Error::new(io::ErrorKind::Other, "no address");
+        for addr in try!((source::unbracket(host), port).to_socket_addrs()) {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => return Ok(HttpStream(stream)),
+                Err(err) => last_err = err,
+            }
+        }
+
+        Err(hyper::Error::Io(last_err))
+    }
+}
+
 fn cappe(sink: &config::Sink, parameters: &config::Parameters) -> Result<(), Box<Error>> {
     let entries = try!(files(&parameters.sink_dir, &sink.name));
     let mut sinks_size: u64 = 0;
+    let mut backlog: u64 = 0;
 
     for entry in &entries {
         let meta = try!(entry.metadata());
@@ -142,35 +1213,118 @@ fn cappe(sink: &config::Sink, parameters: &config::Parameters) -> Result<(), Box
         if sinks_size > sink.size {
             warn!("skip file {:?}", entry.path());
             try!(fs::remove_file(entry.path()));
+            continue;
         }
+        backlog += meta.size();
     }
 
+    stats::set("sink.backlog.bytes", &format!("sink={}", sink.name), backlog);
+
     Ok(())
 }
 
 /// Read a file as String.
+/// Gzip files are decompressed.
 fn read(path: PathBuf) -> Result<String, Box<Error>> {
-    let mut file = try!(File::open(path));
+    let file = try!(File::open(&path));
 
     let mut content = String::new();
-    try!(file.read_to_string(&mut content));
+    if path.extension() == Some(OsStr::new("gz")) {
+        let mut decoder = try!(GzDecoder::new(file));
+        try!(decoder.read_to_string(&mut content));
+    } else {
+        let mut file = file;
+        try!(file.read_to_string(&mut content));
+    }
 
     Ok(content)
 }
 
+/// Delete sink dir files no configured sink owns, they were left by removed sinks.
+pub fn cleanup_orphans(sinks: &Vec<config::Sink>,
+                       parameters: &config::Parameters)
+                       -> Result<(), Box<Error>> {
+    for entry in try!(fs::read_dir(&parameters.sink_dir)) {
+        let entry = try!(entry);
+        let file_name = String::from(entry.file_name().to_str().unwrap_or(""));
+
+        // Claimed and router tmp files are sink files too
+        let name = file_name.trim_right_matches(".processing");
+        let sink_file = name.ends_with(".metrics") || name.ends_with(".metrics.gz") ||
+                        name.ends_with(".tmp");
+        if !sink_file {
+            continue;
+        }
+        let owned = sinks.iter()
+            .any(|sink| owns(name, &sink.name) || name == format!("{}.tmp", sink.name));
+        if owned {
+            continue;
+        }
+
+        warn!("remove orphan sink file {:?}", entry.path());
+        try!(fs::remove_file(entry.path()));
+        stats::incr("sink.files.orphans", "", 1);
+    }
+
+    Ok(())
+}
+
+/// Check if a file belongs to a sink, sink files are named
+/// `<sink>-<timestamp>[.<n>].metrics[.gz][.processing]`.
+fn owns(file_name: &str, sink_name: &str) -> bool {
+    rotation(file_name, sink_name).is_some()
+}
+
+/// Parse a sink file name rotation timestamp and collision counter.
+fn rotation(file_name: &str, sink_name: &str) -> Option<(u64, u64)> {
+    if !file_name.starts_with(sink_name) || !file_name[sink_name.len()..].starts_with('-') {
+        return None;
+    }
+
+    let mut rest = &file_name[sink_name.len() + 1..];
+    if rest.ends_with(".processing") {
+        rest = &rest[..rest.len() - ".processing".len()];
+    }
+    if rest.ends_with(".metrics.gz") {
+        rest = &rest[..rest.len() - ".metrics.gz".len()];
+    } else if rest.ends_with(".metrics") {
+        rest = &rest[..rest.len() - ".metrics".len()];
+    } else {
+        return None;
+    }
+
+    // Timestamp, then the rotation collision counter if any
+    let number = |s: &str| -> Option<u64> {
+        if s.is_empty() || !s.chars().all(|c| c.is_digit(10)) {
+            return None;
+        }
+        s.parse().ok()
+    };
+    let mut parts = rest.splitn(2, '.');
+    let timestamp = match parts.next().and_then(|v| number(v)) {
+        Some(v) => v,
+        None => return None,
+    };
+    match parts.next() {
+        None => Some((timestamp, 0)),
+        Some(n) => number(n).map(|n| (timestamp, n)),
+    }
+}
+
 fn files(dir: &str, sink_name: &str) -> Result<Vec<fs::DirEntry>, Box<Error>> {
     let mut entries: Vec<fs::DirEntry> = try!(fs::read_dir(dir)).filter_map(|entry| {
         if entry.is_err() {
             return None;
         }
         let entry = entry.unwrap();
-        if entry.path().extension() != Some(OsStr::new("metrics")) {
+        let file_name = String::from(entry.file_name().to_str().unwrap_or(""));
+
+        // Handle both plain and compressed files
+        if !file_name.ends_with(".metrics") && !file_name.ends_with(".metrics.gz") {
             return None;
         }
 
-        let file_name = String::from(entry.file_name().to_str().unwrap_or(""));
-
-        if !file_name.starts_with(sink_name) {
+        if !owns(&file_name, sink_name) {
             return None;
         }
 
@@ -181,3 +1335,248 @@ fn files(dir: &str, sink_name: &str) -> Result<Vec<fs::DirEntry>, Box<Error>> {
 
     Ok(entries)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::io::BufReader;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use nix::libc;
+
+    use config;
+    use super::*;
+
+    /// Minimal Warp10 endpoint, answer every push with a status and record its body.
+    fn serve(pushes: Arc<Mutex<Vec<String>>>, status: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || for stream in listener.incoming() {
+            let pushes = pushes.clone();
+            thread::spawn(move || handle(stream.unwrap(), pushes, status));
+        });
+        port
+    }
+
+    /// Answer the requests of a kept alive connection until it is closed.
+    fn handle(stream: TcpStream, pushes: Arc<Mutex<Vec<String>>>, status: &str) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut length = 0;
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if line.to_lowercase().starts_with("content-length:") {
+                    length = line[15..].trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            pushes.lock().unwrap().push(String::from_utf8(body).unwrap());
+            writer.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status)
+                    .as_bytes())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn push_splits_files_bigger_than_max_push_size() {
+        let pushes = Arc::new(Mutex::new(Vec::new()));
+        let port = serve(pushes.clone(), "200 OK");
+
+        let dir = env::temp_dir().join("beamium-sink-max-push-size");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sinks")).unwrap();
+        let path = dir.join("config.yaml");
+        File::create(&path)
+            .unwrap()
+            .write_all(format!("parameters:\n  sink-dir: {}\nsinks:\n  warp:\n    url: \
+                                http://127.0.0.1:{}/api/v0/update\n    token: secret\n    \
+                                max-push-size: 20\n",
+                               dir.join("sinks").display(),
+                               port)
+                .as_bytes())
+            .unwrap();
+        let config = config::load_config(path.to_str().unwrap()).unwrap();
+
+        let metrics = "1// a{} 1\n2// b{} 2\n3// c{} 3\n4// d{} 4\n5// e{} 5\n";
+        let sink_file = dir.join("sinks").join("warp-1000.metrics");
+        File::create(&sink_file).unwrap().write_all(metrics.as_bytes()).unwrap();
+
+        assert!(once(&config.sinks[0], &config.parameters, Arc::new(AtomicBool::new(false))));
+
+        let pushes = pushes.lock().unwrap();
+        assert_eq!(pushes.len(), 3);
+        assert!(pushes.iter().all(|push| push.len() <= 20), "{:?}", *pushes);
+        let pushed = pushes.concat();
+        assert_eq!(pushed.lines().filter(|line| !line.is_empty()).collect::<Vec<&str>>(),
+                   metrics.lines().collect::<Vec<&str>>());
+        assert!(!sink_file.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sink_files_with_overlapping_names() {
+        assert_eq!(rotation("warp-1000.metrics", "warp"), Some((1000, 0)));
+        assert_eq!(rotation("warp-1000.2.metrics.gz", "warp"), Some((1000, 2)));
+        assert_eq!(rotation("warp-1000.2.metrics.processing", "warp"), Some((1000, 2)));
+
+        // Files of sink warp-2 are never owned by sink warp
+        assert_eq!(rotation("warp-2-1700000000.metrics", "warp"), None);
+        assert_eq!(rotation("warp-2-1700000000.1.metrics", "warp"), None);
+        assert_eq!(rotation("warp-2-1700000000.1.metrics", "warp-2"), Some((1700000000, 1)));
+        assert!(!owns("warp.tmp", "warp"));
+    }
+
+    #[test]
+    fn failed_send_releases_claimed_files() {
+        let pushes = Arc::new(Mutex::new(Vec::new()));
+        let port = serve(pushes.clone(), "400 Bad Request");
+
+        // Rejected files can not be moved to a missing dead letter dir
+        let dir = env::temp_dir().join("beamium-sink-release");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sinks")).unwrap();
+        let path = dir.join("config.yaml");
+        File::create(&path)
+            .unwrap()
+            .write_all(format!("parameters:\n  sink-dir: {}\n  dead-letter-dir: {}\nsinks:\n  \
+                                warp:\n    url: http://127.0.0.1:{}/api/v0/update\n    \
+                                token: secret\n",
+                               dir.join("sinks").display(),
+                               dir.join("missing").display(),
+                               port)
+                .as_bytes())
+            .unwrap();
+        let config = config::load_config(path.to_str().unwrap()).unwrap();
+
+        let sink_file = dir.join("sinks").join("warp-1000.metrics");
+        File::create(&sink_file).unwrap().write_all(b"1// a{} 1\n").unwrap();
+
+        assert!(!once(&config.sinks[0], &config.parameters, Arc::new(AtomicBool::new(false))));
+        assert_eq!(pushes.lock().unwrap().len(), 1);
+        assert!(sink_file.exists());
+        assert!(!processing_path(&sink_file).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cleanup_orphans_keeps_overlapping_sink_names() {
+        let dir = env::temp_dir().join("beamium-sink-orphans");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sinks")).unwrap();
+        let path = dir.join("config.yaml");
+        File::create(&path)
+            .unwrap()
+            .write_all(format!("parameters:\n  sink-dir: {}\nsinks:\n  warp:\n    \
+                                url: http://127.0.0.1/\n    token: a\n  warp-2:\n    \
+                                url: http://127.0.0.1/\n    token: b\n",
+                               dir.join("sinks").display())
+                .as_bytes())
+            .unwrap();
+        let config = config::load_config(path.to_str().unwrap()).unwrap();
+
+        let kept = ["warp-1000.metrics",
+                    "warp-1000.1.metrics",
+                    "warp-2-1000.metrics",
+                    "warp-2-1000.1.metrics.processing",
+                    "warp-2.tmp"];
+        let orphans = ["warp-3-1000.metrics", "old-1000.metrics"];
+        for name in kept.iter().chain(orphans.iter()) {
+            File::create(dir.join("sinks").join(name)).unwrap();
+        }
+
+        cleanup_orphans(&config.sinks, &config.parameters).unwrap();
+        for name in &kept {
+            assert!(dir.join("sinks").join(name).exists(), "{} was removed", name);
+        }
+        for name in &orphans {
+            assert!(!dir.join("sinks").join(name).exists(), "{} was kept", name);
+        }
+
+        // Claims only take the sink own files
+        let names: Vec<String> = files(dir.join("sinks").to_str().unwrap(), "warp")
+            .unwrap()
+            .iter()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["warp-1000.metrics", "warp-1000.1.metrics"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Write a file with a given modification time, in seconds.
+    fn write_at(path: &Path, content: &str, mtime: i64) {
+        File::create(path).unwrap().write_all(content.as_bytes()).unwrap();
+        let time = libc::timeval {
+            tv_sec: mtime,
+            tv_usec: 0,
+        };
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::utimes(path.as_ptr(), [time, time].as_ptr()) }, 0);
+    }
+
+    #[test]
+    fn token_file_is_read_again_when_modified() {
+        let dir = env::temp_dir().join("beamium-sink-token");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let token_file = dir.join("token");
+        write_at(&token_file, "first\n", 1000);
+
+        let path = dir.join("config.yaml");
+        File::create(&path)
+            .unwrap()
+            .write_all(format!("sinks:\n  warp:\n    url: http://127.0.0.1/\n    token-file: {}\n",
+                               token_file.display())
+                .as_bytes())
+            .unwrap();
+        let config = config::load_config(path.to_str().unwrap()).unwrap();
+        let mut state = state(&config.sinks[0], &config.parameters);
+        assert_eq!(token(&config.sinks[0], &mut state).unwrap(), "first");
+
+        // Unchanged modification time, the cached token is kept
+        write_at(&token_file, "second\n", 1000);
+        assert_eq!(token(&config.sinks[0], &mut state).unwrap(), "first");
+
+        write_at(&token_file, "second\n", 2000);
+        assert_eq!(token(&config.sinks[0], &mut state).unwrap(), "second");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn graphite_labels_with_braces_and_encoded_values() {
+        let unit = config::TimestampUnit::Micros;
+        assert_eq!(graphite(r#"1000000// cpu{host="a{b}",dc=eu%2Cwest} 3"#, &unit).unwrap(),
+                   "cpu.eu,west.a{b} 3 1");
+        assert_eq!(graphite("2000000// disk%20io{dev=sda.1,empty=} T", &unit).unwrap(),
+                   "disk_io.sda_1 1 2");
+        assert_eq!(graphite("2000000// up 1", &unit).unwrap(), "up 1 2");
+        assert!(graphite("2000000// name{} 'text'", &unit).is_err());
+    }
+
+    #[test]
+    fn meta_series_with_spaces_in_labels() {
+        let metrics = "1// cpu{host=\"a b\",dc=eu} 1\n2// cpu{host=\"a b\",dc=eu} 2\n3// up 1\n";
+        let mut lines: Vec<String> = meta(metrics, "team=ops").lines().map(String::from).collect();
+        lines.sort();
+        assert_eq!(lines,
+                   vec!["cpu{host=\"a b\",dc=eu}{team=ops}", "up{}{team=ops}"]);
+    }
+}
@@ -3,44 +3,237 @@
 //! The Source module fetch metrics to Prometheus.
 use std::thread;
 use std::time::Duration;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Condvar};
 use std::sync::atomic::{AtomicBool, Ordering};
 use time;
 use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use hyper;
+use hyper::header::{Accept, Authorization, Basic, Bearer, AcceptEncoding, ContentEncoding,
+                    ContentType, Encoding, UserAgent, qitem};
+use hyper::status::StatusCode;
+use hyper::net::{HttpStream, HttpsConnector, NetworkConnector, NetworkStream};
+use hyper::client::ProxyConfig;
+use hyper_native_tls::NativeTlsClient;
+use native_tls::TlsConnector;
+use native_tls::backend::openssl::TlsConnectorBuilderExt;
+use openssl::x509::X509_FILETYPE_PEM;
+use openssl::ssl::SSL_VERIFY_NONE;
 use std::io::prelude::*;
+use std::io;
 use std::fs;
 use std::fs::File;
 use std::error::Error;
 use std::path::Path;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use flate2::read::GzDecoder;
 
 use config;
+use router;
+use stats;
+use sink;
 
 /// Thread sleeping time.
 const REST_TIME: u64 = 10;
 
+/// Maximum delay(ms) between two retries.
+const MAX_RETRY_DELAY: u64 = 300000;
+
+lazy_static! {
+    /// Number of running scrapes, shared by every source.
+    static ref SCRAPES: (Mutex<u64>, Condvar) = (Mutex::new(0), Condvar::new());
+
+    /// Resolved addresses, indexed by host and port, with their resolution time in seconds.
+    static ref DNS: Mutex<HashMap<(String, u16), (Vec<SocketAddr>, u64)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Running scrape slot, released on drop.
+struct Permit;
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let &(ref lock, ref cvar) = &*SCRAPES;
+        *lock.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        cvar.notify_one();
+    }
+}
+
+/// Wait for a scrape slot, sources queue rather than skip a scrape.
+/// Return None on shutdown.
+fn acquire(limit: u64, sigint: &Arc<AtomicBool>) -> Option<Permit> {
+    let &(ref lock, ref cvar) = &*SCRAPES;
+    let mut running = lock.lock().unwrap_or_else(|e| e.into_inner());
+    while *running >= limit {
+        if sigint.load(Ordering::Relaxed) {
+            return None;
+        }
+        running = cvar.wait_timeout(running, Duration::from_millis(REST_TIME))
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+    }
+    *running += 1;
+
+    Some(Permit)
+}
+
 /// Source loop.
 pub fn source(source: &config::Source, parameters: &config::Parameters, sigint: Arc<AtomicBool>) {
+    // Spread sources scrapes
+    let delay = prepare(source, parameters);
+    for _ in 0..delay / REST_TIME {
+        thread::sleep(Duration::from_millis(REST_TIME));
+        if sigint.load(Ordering::Relaxed) {
+            return;
+        }
+    }
+
     loop {
-        let start = time::now_utc();
+        let sleep_time = scrape(source, parameters, &sigint);
+        for _ in 0..sleep_time / REST_TIME {
+            thread::sleep(Duration::from_millis(REST_TIME));
+            if sigint.load(Ordering::Relaxed) {
+                return;
+            }
+        }
+    }
+}
+
+/// Prepare a source before its first scrape, return the first scrape delay.
+pub fn prepare(source: &config::Source, parameters: &config::Parameters) -> u64 {
+    if source.insecure {
+        warn!("TLS certificate verification is disabled");
+    }
 
-        match fetch(source, parameters) {
-            Err(err) => error!("fetch fail: {}", err),
-            Ok(_) => info!("fetch success"),
+    if let Some(ref dir) = source.dir {
+        if let Err(err) = sink::create_dir(dir, parameters) {
+            error!("fail to create source directory {}: {}", dir, err);
         }
+    }
 
-        let elapsed = (time::now_utc() - start).num_milliseconds() as u64;
-        let sleep_time = if elapsed > source.period {
-            REST_TIME
-        } else {
-            cmp::max(source.period - elapsed, REST_TIME)
+    // Zero until the first success, so a never scraped source stands out
+    stats::set("source.last_success", &format!("source={}", source.name), 0);
+
+    let delay = jitter(source, parameters);
+    debug!("delay first scrape by {}ms", delay);
+    delay
+}
+
+/// Scrape a source once, return the delay before the next scrape.
+pub fn scrape(source: &config::Source,
+              parameters: &config::Parameters,
+              sigint: &Arc<AtomicBool>)
+              -> u64 {
+    let start = time::now_utc();
+
+    match fetch_retry(source, parameters, sigint) {
+        Err(ref err) if is_timeout(err) => warn!("fetch timeout for {}", source.name),
+        Err(ref err) if source.retry > 0 => warn!("fetch fail after retries: {}", err),
+        Err(err) => error!("fetch fail: {}", err),
+        Ok(_) => {
+            info!("fetch success");
+            success(source);
+        }
+    }
+
+    let elapsed = (time::now_utc() - start).num_milliseconds() as u64;
+    if elapsed > source.period {
+        REST_TIME
+    } else {
+        cmp::max(source.period - elapsed, REST_TIME)
+    }
+}
+
+/// Scrape a source once, without jitter.
+pub fn once(source: &config::Source,
+            parameters: &config::Parameters,
+            sigint: &Arc<AtomicBool>)
+            -> bool {
+    prepare(source, parameters);
+
+    match fetch_retry(source, parameters, sigint) {
+        Err(err) => {
+            error!("fetch fail: {}", err);
+            false
+        }
+        Ok(_) => {
+            info!("fetch success");
+            success(source);
+            true
+        }
+    }
+}
+
+/// Record a source last successful scrape, as seconds since epoch.
+fn success(source: &config::Source) {
+    stats::set("source.last_success",
+               &format!("source={}", source.name),
+               time::now_utc().to_timespec().sec as u64);
+}
+
+/// Compute the source first scrape delay.
+/// The delay only depends on the source name when a seed is set, without a seed the
+/// clock is hashed in so offsets change on every restart.
+fn jitter(source: &config::Source, parameters: &config::Parameters) -> u64 {
+    if parameters.scrape_jitter == 0 {
+        return 0;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    source.name.hash(&mut hasher);
+    match parameters.jitter_seed {
+        Some(seed) => seed.hash(&mut hasher),
+        None => time::precise_time_ns().hash(&mut hasher),
+    }
+
+    hasher.finish() % (parameters.scrape_jitter + 1)
+}
+
+/// Fetch with exponential backoff retries.
+/// Retries never overrun the source period.
+fn fetch_retry(source: &config::Source,
+               parameters: &config::Parameters,
+               sigint: &Arc<AtomicBool>)
+               -> Result<(), Box<Error>> {
+    let start = time::now_utc();
+    let mut delay = source.retry_delay;
+    let mut attempt = 0;
+
+    loop {
+        let result = {
+            let _permit = match parameters.scrape_concurrency {
+                None => None,
+                Some(limit) => {
+                    match acquire(limit, sigint) {
+                        None => return Err(From::from("interrupted")),
+                        permit => permit,
+                    }
+                }
+            };
+            fetch(source, parameters)
         };
-        for _ in 0..sleep_time / REST_TIME {
+        let err = match result {
+            Ok(v) => return Ok(v),
+            Err(err) => err,
+        };
+
+        let elapsed = (time::now_utc() - start).num_milliseconds() as u64;
+        if attempt >= source.retry || elapsed + delay > source.period {
+            return Err(err);
+        }
+
+        attempt += 1;
+        debug!("fetch attempt {} fail: {}, retry in {}ms", attempt, err, delay);
+        for _ in 0..delay / REST_TIME {
             thread::sleep(Duration::from_millis(REST_TIME));
             if sigint.load(Ordering::Relaxed) {
-                return;
+                return Err(err);
             }
         }
+        delay = cmp::min(delay.saturating_mul(2), MAX_RETRY_DELAY);
     }
 }
 
@@ -49,42 +242,190 @@ fn fetch(source: &config::Source, parameters: &config::Parameters) -> Result<(),
     debug!("fetch {}", &source.url);
 
     // Fetch metrics
-    let mut client = hyper::Client::new();
-    client.set_write_timeout(Some(Duration::from_secs(parameters.timeout)));
-    client.set_read_timeout(Some(Duration::from_secs(parameters.timeout)));
+    let mut client = try!(client(source, parameters));
+    let url = match try!(unix_url(&source.url)) {
+        Some((_, url)) => url,
+        None => source.url.clone(),
+    };
+    let timeout = source.timeout
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::from_secs(parameters.timeout));
+    client.set_write_timeout(Some(timeout));
+    client.set_read_timeout(Some(timeout));
+
+    // Scrape each path, a failing path does not abort the others
+    let fetch_start = time::now_utc();
+    let body = if source.paths.is_empty() {
+        try!(get(source, parameters, &client, &url))
+    } else {
+        let mut body = String::new();
+        let mut error = None;
+        let mut succeeded = false;
+        for path in &source.paths {
+            let mut path_url = try!(hyper::Url::parse(&url));
+            path_url.set_path(path);
+            match get(source, parameters, &client, path_url.as_str()) {
+                Err(err) => {
+                    warn!("fetch {} fail: {}", path, err);
+                    error = Some(err);
+                }
+                Ok(v) => {
+                    body.push_str(&v);
+                    if !body.ends_with('\n') {
+                        body.push('\n');
+                    }
+                    succeeded = true;
+                }
+            }
+        }
+        match error {
+            Some(err) => {
+                if !succeeded {
+                    return Err(err);
+                }
+                body
+            }
+            None => body,
+        }
+    };
+
+    let duration = (time::now_utc() - fetch_start).num_milliseconds() as u64;
+    debug!("scrape duration_ms={} bytes={}", duration, body.len());
+    let labels = format!("source={}", source.name);
+    stats::set("scrape.duration", &labels, duration);
+    stats::set("scrape.bytes", &labels, body.len() as u64);
+
+    dump(source, parameters, &body)
+}
+
+/// Request a source url, return the response body.
+fn get(source: &config::Source,
+       parameters: &config::Parameters,
+       client: &hyper::Client,
+       url: &str)
+       -> Result<String, Box<Error>> {
+    debug!("get {}", url);
 
-    let mut res = try!(client.get(&source.url).send());
+    let mut headers = hyper::header::Headers::new();
+    headers.set(AcceptEncoding(vec![qitem(Encoding::Gzip)]));
+    let accept = match source.scrape_protocol {
+        config::ScrapeProtocol::Prometheus => "text/plain; version=0.0.4",
+        config::ScrapeProtocol::OpenMetrics => "application/openmetrics-text; version=1.0.0",
+    };
+    headers.set(Accept(vec![qitem(try!(accept.parse().map_err(|_| "bad accept header")))]));
+    headers.set(UserAgent(source.user_agent.clone().unwrap_or(parameters.user_agent.clone())));
+    for (k, v) in &source.headers {
+        debug!("set header {}: {}", k, if is_secret(k) { "<redacted>" } else { v });
+        headers.set_raw(k.clone(), vec![v.as_bytes().to_vec()]);
+    }
+    if let Some(token) = try!(token(source)) {
+        match source.token_header {
+            None => headers.set(Authorization(Bearer { token: token })),
+            Some(ref header) => headers.set_raw(header.clone(), vec![token.into()]),
+        }
+    }
+    // Inside a CONNECT tunnel the header would reach the source instead of the proxy
+    if source.url.starts_with("http:") {
+        if let Some(auth) = proxy_auth(source) {
+            headers.set_raw("Proxy-Authorization", vec![auth.into_bytes()]);
+        }
+    }
+    if let Some(ref auth) = source.basic_auth {
+        headers.set(Authorization(Basic {
+            username: auth.username.clone(),
+            password: try!(password(auth)),
+        }));
+    }
+
+    let request = match source.method {
+        config::Method::Get => client.get(url),
+        config::Method::Post => {
+            let content_type = try!(source.content_type
+                .parse()
+                .map_err(|_| "bad content type"));
+            headers.set(ContentType(content_type));
+            client.post(url).body(source.body.as_ref().map_or("", |body| body.as_str()))
+        }
+    };
+
+    let res = try!(request.headers(headers).send());
+    if res.status == StatusCode::Unauthorized || res.status == StatusCode::Forbidden {
+        return Err(From::from(format!("authentication failed: {}", res.status)));
+    }
     if !res.status.is_success() {
         return Err(From::from("non 200 received"));
     }
 
-    // Read body
-    let mut body = String::new();
-    try!(res.read_to_string(&mut body));
+    // Read body, servers may ignore Accept-Encoding and send plain text
+    let gzip = res.headers
+        .get::<ContentEncoding>()
+        .map_or(false, |encodings| encodings.contains(&Encoding::Gzip));
+    // The limit applies to the decompressed body, so a small gzip body can not expand unbounded.
+    // Read one byte more than allowed to detect oversized responses, as bytes since the
+    // limit can cut a multibyte character
+    let mut raw = Vec::new();
+    if gzip {
+        let decoder = try!(GzDecoder::new(res));
+        try!(decoder.take(source.max_response_size + 1).read_to_end(&mut raw));
+    } else {
+        try!(res.take(source.max_response_size + 1).read_to_end(&mut raw));
+    }
+    if raw.len() as u64 > source.max_response_size {
+        warn!("response exceed {} bytes, abort scrape", source.max_response_size);
+        return Err(From::from("response too large"));
+    }
+    let body = try!(String::from_utf8(raw).map_err(|_| "response is not valid UTF-8"));
     trace!("data {}", &body);
 
+    Ok(body)
+}
 
-    // Get now as millis
+/// Write a scrape body as a source file.
+fn dump(source: &config::Source,
+        parameters: &config::Parameters,
+        body: &str)
+        -> Result<(), Box<Error>> {
+    // Get now as micros
     let start = time::now_utc();
     let now = start.to_timespec().sec * 1000 * 1000 + (start.to_timespec().nsec as i64 / 1000);
+    let scrape_time = parameters.timestamp_unit.from_micros(now);
 
-    let dir = Path::new(&parameters.source_dir);
+    let dir = Path::new(source.dir.as_ref().unwrap_or(&parameters.source_dir));
     let temp_file = dir.join(format!("{}.tmp", source.name));
     debug!("write to tmp file {}", format!("{:?}", temp_file));
     {
         // Open tmp file
-        let mut file = try!(File::create(&temp_file));
+        let mut file = try!(sink::create_file(&temp_file, parameters));
 
+        let mut written = 0;
+        let mut dropped = 0;
         for line in body.lines() {
+            // Strip comments, labels values may contain # but never start a line
+            if line.trim().starts_with('#') {
+                if source.keep_comments {
+                    try!(file.write(line.trim().as_bytes()));
+                    try!(file.write(b"\n"));
+                }
+                continue;
+            }
+
             let line = match source.format {
                 config::SourceFormat::Sensision => String::from(line.trim()),
                 config::SourceFormat::Prometheus => {
-                    match format_prometheus(line.trim(), now) {
+                    let (sample, exemplar) = split_exemplar(line.trim());
+                    let sample = match format_prometheus(sample.trim(),
+                                                         scrape_time,
+                                                         &parameters.timestamp_unit,
+                                                         &source.scrape_protocol) {
                         Err(_) => {
                             warn!("bad row {}", &line);
                             continue;
                         }
                         Ok(v) => v,
+                    };
+                    match exemplar {
+                        Some(exemplar) if source.keep_exemplars => sample + " " + exemplar,
+                        _ => sample,
                     }
                 }
             };
@@ -99,23 +440,426 @@ fn fetch(source: &config::Source, parameters: &config::Parameters) -> Result<(),
                 }
             }
 
+            let line = if parameters.sanitize_le {
+                sanitize_le(&line)
+            } else {
+                line
+            };
+
+            if !keep(&line, source) {
+                stats::incr("source.filtered", &format!("source={}", source.name), 1);
+                continue;
+            }
+
+            let line = if source.relabel.is_empty() {
+                line
+            } else {
+                match relabel(&line, &source.relabel) {
+                    Err(err) => {
+                        warn!("relabel fail for row {}: {}", &line, err);
+                        stats::incr("source.dropped",
+                                    &format!("source={},reason=relabel", source.name),
+                                    1);
+                        continue;
+                    }
+                    Ok(v) => v,
+                }
+            };
+
+            // Runaway cardinality safety valve
+            if source.max_metrics_per_scrape.map_or(false, |max| written >= max) {
+                dropped += 1;
+                continue;
+            }
+
             try!(file.write(line.as_bytes()));
             try!(file.write(b"\n"));
+            written += 1;
+        }
+
+        if dropped > 0 {
+            warn!("scrape exceed {} metrics, {} dropped",
+                  source.max_metrics_per_scrape.unwrap_or(0),
+                  dropped);
+            stats::incr("source.dropped",
+                        &format!("source={},reason=limit", source.name),
+                        dropped);
         }
 
         try!(file.flush());
     }
 
     // Rotate source file
-    let dest_file = dir.join(format!("{}-{}.metrics", source.name, now));
+    let dest_file = dir.join(format!("{}-{}.{}", source.name, now, parameters.source_extension));
     debug!("rotate tmp file to {}", format!("{:?}", dest_file));
     try!(fs::rename(&temp_file, &dest_file));
 
     Ok(())
 }
 
+/// Check if a header is likely to carry a secret.
+fn is_secret(header: &str) -> bool {
+    let header = header.to_lowercase();
+    ["auth", "token", "key", "secret", "password", "cookie"]
+        .iter()
+        .any(|word| header.contains(word))
+}
+
+/// Check if an error is a network timeout.
+pub fn is_timeout(err: &Box<Error>) -> bool {
+    let kind = match err.downcast_ref::<hyper::Error>() {
+        Some(&hyper::Error::Io(ref err)) => err.kind(),
+        _ => {
+            match err.downcast_ref::<io::Error>() {
+                Some(err) => err.kind(),
+                None => return false,
+            }
+        }
+    };
+
+    kind == io::ErrorKind::TimedOut || kind == io::ErrorKind::WouldBlock
+}
+
+/// Build source HTTP client, handle client certificate and custom CA.
+fn client(source: &config::Source,
+          parameters: &config::Parameters)
+          -> Result<hyper::Client, Box<Error>> {
+    let mut builder = try!(TlsConnector::builder());
+    {
+        let ssl = builder.builder_mut().builder_mut();
+        if let Some(ref ca) = source.tls_ca {
+            try!(ssl.set_ca_file(ca));
+        }
+        if let Some(ref cert) = source.tls_cert {
+            try!(ssl.set_certificate_chain_file(cert));
+        }
+        if let Some(ref key) = source.tls_key {
+            try!(ssl.set_private_key_file(key, X509_FILETYPE_PEM));
+        }
+        if source.insecure {
+            ssl.set_verify(SSL_VERIFY_NONE);
+        }
+    }
+
+    let ssl = NativeTlsClient::from(try!(builder.build()));
+    if let Some((socket, _)) = try!(unix_url(&source.url)) {
+        return Ok(hyper::Client::with_connector(UnixConnector { path: socket }));
+    }
+
+    let connector = DnsConnector { ttl: parameters.dns_cache_ttl };
+    let proxy = source.proxy.as_ref().and_then(|proxy| hyper::Url::parse(proxy).ok());
+    if let Some(proxy) = proxy {
+        let host = String::from(proxy.host_str().unwrap_or_default());
+        let port = proxy.port_or_known_default().unwrap_or(80);
+        let config = ProxyConfig::new(proxy.scheme(), host, port, connector, ssl);
+        return Ok(hyper::Client::with_proxy_config(config));
+    }
+
+    Ok(hyper::Client::with_connector(HttpsConnector::with_connector(ssl, connector)))
+}
+
+/// Proxy-Authorization header value from the source proxy url credentials.
+fn proxy_auth(source: &config::Source) -> Option<String> {
+    let url = match source.proxy.as_ref().and_then(|proxy| hyper::Url::parse(proxy).ok()) {
+        Some(url) => url,
+        None => return None,
+    };
+    if url.username().is_empty() {
+        return None;
+    }
+
+    let auth = Authorization(Basic {
+        username: String::from(url.username()),
+        password: url.password().map(String::from),
+    });
+    let mut headers = hyper::header::Headers::new();
+    headers.set(auth);
+    headers.get_raw("Authorization")
+        .and_then(|raw| raw.first())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// HTTP connector resolving hosts through the DNS cache.
+struct DnsConnector {
+    /// Cache ttl in seconds, None disable the cache.
+    ttl: Option<u64>,
+}
+
+impl NetworkConnector for DnsConnector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        if scheme != "http" && scheme != "https" {
+            return Err(hyper::Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                       "invalid scheme for http")));
+        }
+
+        let addrs = try!(resolve(host, port, self.ttl));
+        Ok(HttpStream(try!(TcpStream::connect(&addrs[..]))))
+    }
+}
+
+/// Parse a Unix domain socket url, `unix:///path/to.sock?path=/metrics`.
+/// Return the socket path and the HTTP url to request through it, None for other urls.
+fn unix_url(url: &str) -> Result<Option<(String, String)>, Box<Error>> {
+    if !url.starts_with("unix:") {
+        return Ok(None);
+    }
+
+    let parsed = try!(hyper::Url::parse(url));
+    let mut http = try!(hyper::Url::parse("http://localhost/metrics"));
+    let mut query = Vec::new();
+    for (k, v) in parsed.query_pairs() {
+        if k == "path" {
+            http.set_path(&v);
+        } else {
+            query.push((k.into_owned(), v.into_owned()));
+        }
+    }
+    if !query.is_empty() {
+        http.query_pairs_mut().extend_pairs(query);
+    }
+
+    Ok(Some((String::from(parsed.path()), http.into_string())))
+}
+
+/// HTTP connector to a Unix domain socket, requested host and port are ignored.
+struct UnixConnector {
+    path: String,
+}
+
+impl NetworkConnector for UnixConnector {
+    type Stream = UnixSocket;
+
+    fn connect(&self, _: &str, _: u16, _: &str) -> hyper::Result<UnixSocket> {
+        Ok(UnixSocket(try!(UnixStream::connect(&self.path))))
+    }
+}
+
+/// Unix domain socket HTTP stream.
+struct UnixSocket(UnixStream);
+
+impl Read for UnixSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for UnixSocket {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Other, "unix socket has no peer address"))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+}
+
+/// Resolve a host, literal addresses are never cached.
+fn resolve(host: &str, port: u16, ttl: Option<u64>) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = unbracket(host).parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let ttl = match ttl {
+        None => return Ok(try!((host, port).to_socket_addrs()).collect()),
+        Some(ttl) => ttl,
+    };
+
+    let now = time::precise_time_ns() / 1000 / 1000 / 1000;
+    let key = (String::from(host), port);
+    if let Some(&(ref addrs, resolved)) = DNS.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        if now - resolved < ttl {
+            return Ok(addrs.clone());
+        }
+    }
+
+    debug!("resolve {}", host);
+    let addrs: Vec<SocketAddr> = try!((host, port).to_socket_addrs()).collect();
+    DNS.lock().unwrap_or_else(|e| e.into_inner()).insert(key, (addrs.clone(), now));
+
+    Ok(addrs)
+}
+
+/// Strip the brackets of an IPv6 literal url host, `[::1]` becomes `::1`.
+pub fn unbracket(host: &str) -> &str {
+    if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    }
+}
+
+/// Get source token, token file is read each time to handle rotation.
+fn token(source: &config::Source) -> Result<Option<String>, Box<Error>> {
+    if let Some(ref path) = source.token_file {
+        let mut file = try!(File::open(path));
+        let mut token = String::new();
+        try!(file.read_to_string(&mut token));
+        return Ok(Some(String::from(token.trim())));
+    }
+
+    Ok(source.token.clone())
+}
+
+/// Check a Sensision line class against source allow and deny lists.
+/// Deny takes precedence over allow.
+fn keep(line: &str, source: &config::Source) -> bool {
+    if source.metrics_allow.is_none() && source.metrics_deny.is_none() {
+        return true;
+    }
+
+    let class = router::parse_metric_line(line).map(|metric| metric.class).unwrap_or("");
+
+    if let Some(ref deny) = source.metrics_deny {
+        if deny.is_match(class) {
+            return false;
+        }
+    }
+
+    match source.metrics_allow {
+        Some(ref allow) => allow.is_match(class),
+        None => true,
+    }
+}
+
+/// Rewrite `le=+Inf` histogram bucket label to `le=Inf`.
+/// Only `_bucket` classes are rewritten, other bucket boundaries are kept as is.
+fn sanitize_le(line: &str) -> String {
+    let metric = match router::parse_metric_line(line) {
+        Ok(metric) => metric,
+        Err(_) => return String::from(line),
+    };
+    if !metric.class.ends_with("_bucket") {
+        return String::from(line);
+    }
+    let labels = match router::parse_labels(metric.labels.unwrap_or("")) {
+        Ok(labels) => labels,
+        Err(_) => return String::from(line),
+    };
+    if !labels.iter().any(|&(ref k, ref v)| k == "le" && v == "+Inf") {
+        return String::from(line);
+    }
+
+    let labels = labels.iter()
+        .map(|&(ref k, ref v)| {
+            let v = if k == "le" && v == "+Inf" { "Inf" } else { v };
+            format!("{}={}", k, v)
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+
+    metric.format(metric.class, &labels)
+}
+
+/// Apply relabel rules to a Sensision line.
+fn relabel(line: &str, rules: &[config::Relabel]) -> Result<String, Box<Error>> {
+    let metric = try!(router::parse_metric_line(line));
+    let mut class = String::from(metric.class);
+    let mut labels = try!(router::parse_labels(metric.labels.unwrap_or("")));
+
+    for rule in rules {
+        let value = if rule.source_label == "__name__" {
+            Some(class.clone())
+        } else {
+            labels.iter().find(|&&(ref k, _)| *k == rule.source_label).map(|&(_, ref v)| v.clone())
+        };
+        let value = match value {
+            Some(v) => v,
+            None => continue,
+        };
+        if !rule.regex.is_match(&value) {
+            continue;
+        }
+
+        match rule.action {
+            config::RelabelAction::Drop => labels.retain(|&(ref k, _)| *k != rule.source_label),
+            config::RelabelAction::Rename => {
+                labels.retain(|&(ref k, _)| *k != rule.source_label);
+                set_label(&mut labels, rule.target_label.as_ref().unwrap(), value);
+            }
+            config::RelabelAction::Replace => {
+                let target = rule.target_label.as_ref().unwrap();
+                let value = rule.regex.replace(&value, rule.replacement.as_str());
+                if target == "__name__" {
+                    class = value;
+                } else {
+                    set_label(&mut labels, target, value);
+                }
+            }
+        }
+    }
+
+    let labels = labels.iter()
+        .map(|&(ref k, ref v)| format!("{}={}", k, v))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    Ok(metric.format(&class, &labels))
+}
+
+/// Set a label value, add it if missing.
+fn set_label(labels: &mut Vec<(String, String)>, key: &str, value: String) {
+    match labels.iter().position(|&(ref k, _)| k == key) {
+        Some(i) => labels[i].1 = value,
+        None => labels.push((String::from(key), value)),
+    }
+}
+
+/// Get source basic auth password, password file is read each time to handle rotation.
+fn password(auth: &config::BasicAuth) -> Result<Option<String>, Box<Error>> {
+    if let Some(ref path) = auth.password_file {
+        let mut file = try!(File::open(path));
+        let mut password = String::new();
+        try!(file.read_to_string(&mut password));
+        return Ok(Some(String::from(password.trim())));
+    }
+
+    Ok(auth.password.clone())
+}
+
+/// Split an OpenMetrics sample line from its exemplar, if any.
+/// A `#` inside a label value does not start an exemplar.
+fn split_exemplar(line: &str) -> (&str, Option<&str>) {
+    let (mut quoted, mut escaped) = (false, false);
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted && i > 0 => return (&line[..i], Some(&line[i..])),
+            _ => {}
+        }
+    }
+
+    (line, None)
+}
+
 /// Format Warp10 metrics from Prometheus one.
-fn format_prometheus(line: &str, now: i64) -> Result<String, Box<Error>> {
+/// Prometheus timestamps are in milliseconds, OpenMetrics ones in seconds,
+/// scrape time is used when missing.
+fn format_prometheus(line: &str,
+                     now: i64,
+                     unit: &config::TimestampUnit,
+                     protocol: &config::ScrapeProtocol)
+                     -> Result<String, Box<Error>> {
     // Skip comments
     if line.starts_with("#") {
         return Ok(String::new());
@@ -132,16 +876,24 @@ fn format_prometheus(line: &str, now: i64) -> Result<String, Box<Error>> {
 
     let value = try!(tokens.next().ok_or("no value"));
     let timestamp = tokens.next()
-        .map(|v| {
-            i64::from_str_radix(v, 10)
-                .map(|v| v * 1000 * 1000)
-                .unwrap_or(now)
+        .map(|v| match *protocol {
+            // Milliseconds, earlier versions multiplied them by 1000000 whatever the unit
+            config::ScrapeProtocol::Prometheus => {
+                i64::from_str_radix(v, 10)
+                    .map(|v| unit.from_millis(v))
+                    .unwrap_or(now)
+            }
+            config::ScrapeProtocol::OpenMetrics => {
+                v.parse::<f64>()
+                    .map(|v| unit.from_millis((v * 1000.0) as i64))
+                    .unwrap_or(now)
+            }
         })
         .unwrap_or(now);
 
     // Format class
     let mut parts = class.splitn(2, "{");
-    let class = String::from(try!(parts.next().ok_or("no_class")));
+    let class = String::from(try!(parts.next().ok_or("no_class")).trim());
     let plabels = parts.next();
     let slabels = if plabels.is_some() {
         let mut labels = plabels.unwrap().split("\",")
@@ -170,3 +922,62 @@ fn format_prometheus(line: &str, now: i64) -> Result<String, Box<Error>> {
 
     Ok(format!("{}// {} {}", timestamp, class, value))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+    use super::*;
+
+    #[test]
+    fn unbracket_ipv6_hosts() {
+        assert_eq!(unbracket("[::1]"), "::1");
+        assert_eq!(unbracket("[fe80::1]"), "fe80::1");
+        assert_eq!(unbracket("127.0.0.1"), "127.0.0.1");
+        assert_eq!(unbracket("localhost"), "localhost");
+    }
+
+    #[test]
+    fn resolve_bracketed_ipv6_literal() {
+        let addrs = resolve("[::1]", 9100, Some(60)).unwrap();
+        assert_eq!(addrs,
+                   vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 9100)]);
+
+        // Literal addresses are never cached
+        let dns = DNS.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(!dns.contains_key(&(String::from("[::1]"), 9100)));
+    }
+
+    /// Format a Prometheus sample scraped at 42.
+    fn sample(line: &str, unit: config::TimestampUnit) -> String {
+        format_prometheus(line, 42, &unit, &config::ScrapeProtocol::Prometheus).unwrap()
+    }
+
+    #[test]
+    fn prometheus_timestamp_present() {
+        assert_eq!(sample("up{job=\"node\"} 1 1500", config::TimestampUnit::Micros),
+                   "1500000// up{job=node} 1");
+        assert_eq!(sample("up 1 1500", config::TimestampUnit::Millis), "1500// up{} 1");
+        assert_eq!(sample("up 1 1500", config::TimestampUnit::Nanos), "1500000000// up{} 1");
+        assert_eq!(format_prometheus("up 1 1.5",
+                                     42,
+                                     &config::TimestampUnit::Micros,
+                                     &config::ScrapeProtocol::OpenMetrics)
+                       .unwrap(),
+                   "1500000// up{} 1");
+    }
+
+    #[test]
+    fn prometheus_timestamp_absent() {
+        assert_eq!(sample("up{job=\"node\"} 1", config::TimestampUnit::Micros),
+                   "42// up{job=node} 1");
+        assert_eq!(sample("up 1", config::TimestampUnit::Micros), "42// up{} 1");
+    }
+
+    #[test]
+    fn prometheus_timestamp_negative_and_zero() {
+        assert_eq!(sample("up 1 -1500", config::TimestampUnit::Micros), "-1500000// up{} 1");
+        assert_eq!(sample("up 1 0", config::TimestampUnit::Micros), "0// up{} 1");
+        assert_eq!(sample("up 1 0", config::TimestampUnit::Nanos), "0// up{} 1");
+    }
+}
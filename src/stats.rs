@@ -0,0 +1,128 @@
+//! # Stats module.
+//!
+//! The Stats module keep track of beamium own metrics and write them as a source.
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use time;
+use std::cmp;
+use std::io::prelude::*;
+use std::fs;
+use std::error::Error;
+use std::path::Path;
+
+use config;
+use sink;
+
+/// Thread sleeping time.
+const REST_TIME: u64 = 10;
+
+/// Delay between two stats dumps.
+const PERIOD: u64 = 10000;
+
+/// Classes prefix.
+const PREFIX: &'static str = "beamium.";
+
+lazy_static! {
+    /// Metrics values, indexed by class and labels.
+    static ref METRICS: Mutex<HashMap<(String, String), u64>> = Mutex::new(HashMap::new());
+}
+
+/// Increment a counter.
+pub fn incr(class: &str, labels: &str, value: u64) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics.entry((String::from(class), String::from(labels))).or_insert(0) += value;
+}
+
+/// Set a gauge.
+pub fn set(class: &str, labels: &str, value: u64) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.insert((String::from(class), String::from(labels)), value);
+}
+
+/// Render metrics in Prometheus exposition format.
+pub fn prometheus() -> String {
+    let metrics = METRICS.lock().unwrap();
+    metrics.iter()
+        .fold(String::new(), |acc, (&(ref class, ref labels), value)| {
+            let class = (String::from(PREFIX) + class).replace(".", "_");
+            let labels = labels.split(',')
+                .filter_map(|label| {
+                    let mut parts = label.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(k), Some(v)) => Some(format!("{}=\"{}\"", k, v)),
+                        _ => None,
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(",");
+            acc + &format!("{}{{{}}} {}\n", class, labels, value)
+        })
+}
+
+/// Stats loop.
+pub fn stats(parameters: &config::Parameters, sigint: Arc<AtomicBool>) {
+    loop {
+        let start = time::now_utc();
+
+        match dump(parameters) {
+            Err(err) => error!("dump fail: {}", err),
+            Ok(_) => debug!("dump success"),
+        }
+
+        let elapsed = (time::now_utc() - start).num_milliseconds() as u64;
+        let sleep_time = if elapsed > PERIOD {
+            REST_TIME
+        } else {
+            cmp::max(PERIOD - elapsed, REST_TIME)
+        };
+        for _ in 0..sleep_time / REST_TIME {
+            thread::sleep(Duration::from_millis(REST_TIME));
+            if sigint.load(Ordering::Relaxed) {
+                return;
+            }
+        }
+    }
+}
+
+/// Dump stats as a Sensision source file.
+fn dump(parameters: &config::Parameters) -> Result<(), Box<Error>> {
+    // Get now as micros
+    let start = time::now_utc();
+    let now = start.to_timespec().sec * 1000 * 1000 + (start.to_timespec().nsec as i64 / 1000);
+
+    let lines: Vec<String> = {
+        let metrics = METRICS.lock().unwrap();
+        metrics.iter()
+            .map(|(&(ref class, ref labels), value)| {
+                format!("{}// {}{}{{{}}} {}",
+                        parameters.timestamp_unit.from_micros(now),
+                        PREFIX,
+                        class,
+                        labels,
+                        value)
+            })
+            .collect()
+    };
+
+    let dir = Path::new(&parameters.source_dir);
+    let temp_file = dir.join("beamium-stats.tmp");
+    debug!("write to tmp file {}", format!("{:?}", temp_file));
+    {
+        let mut file = try!(sink::create_file(&temp_file, parameters));
+        for line in lines {
+            try!(file.write_all(line.as_bytes()));
+            try!(file.write_all(b"\n"));
+        }
+        try!(file.flush());
+    }
+
+    // Rotate stats file
+    let dest_file = dir.join(format!("beamium-stats-{}.{}", now, parameters.source_extension));
+    debug!("rotate tmp file to {}", format!("{:?}", dest_file));
+    try!(fs::rename(&temp_file, &dest_file));
+
+    Ok(())
+}